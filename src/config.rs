@@ -0,0 +1,492 @@
+//! Declarative relay configuration, the `--config` TOML file
+//!
+//! ```toml
+//! source = "file:/dev/ttyUSB0"
+//! metadata = "metadata"
+//! clock_offset = "reception"
+//! fan_out = false
+//! live_timer = 100000
+//! channel_capacity = 64
+//! drain_timeout = 5
+//!
+//! [device]
+//! baud_rate = 921600
+//! flow_control = "hw"
+//! framing = "hdlc"
+//! dtr = false
+//!
+//! [relayd]
+//! control = "127.0.0.1:5342"
+//! data = "127.0.0.1:5343"
+//!
+//! [[stream_mapping]]
+//! session_name = "system"
+//! pathname = "system-$DATETIME"
+//! stream_ids = ["0-15", 42]
+//! stream_instance_ids = [0]
+//! sources = ["10.0.0.2"]
+//! relayds = [{ control = "10.0.0.5:5342", data = "10.0.0.5:5343" }]
+//! ```
+//!
+//! Several sources are listed as `[[sources]]` instead of `source`, the top-level
+//! `metadata`, `clock_offset` and `[device]` are their defaults:
+//!
+//! ```toml
+//! metadata = "metadata"
+//!
+//! [[sources]]
+//! url = "file:/dev/ttyUSB0"
+//!
+//! [[sources]]
+//! url = "file:/dev/ttyUSB1"
+//! metadata = "other-firmware-metadata"
+//! device = { baud_rate = 921600, framing = "slip", console = "boot.log" }
+//! ```
+
+use crate::console::ConsoleOutput;
+use crate::metadata::{ClockOffset, MetadataSource};
+use crate::serial::DeviceOpts;
+use crate::stream_mapping::{
+    check_stream_mappings, expand_pathname, parse_range, RelaydEndpoint, StreamMapping,
+    StreamSelector,
+};
+use crate::DeviceOrSocket;
+use serde::Deserialize;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fs, io};
+use thiserror::Error;
+use toml::Spanned;
+
+/// Default number of packets buffered for each session before the source waits
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 64;
+
+/// Default seconds given to the packets in flight to reach relayd on shutdown
+pub const DEFAULT_DRAIN_TIMEOUT_SECS: u64 = 5;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("Failed to read the config file '{0}'. {1}")]
+    Io(PathBuf, io::Error),
+
+    #[error("{0}:{1}:{2}: {3}")]
+    Invalid(PathBuf, usize, usize, String),
+}
+
+/// Everything the relay needs to run, from the command line or a config file
+#[derive(Debug, Clone)]
+pub struct RelayConfig {
+    /// Each source is read independently, their packets share the stream mappings
+    pub sources: Vec<SourceConfig>,
+    pub fan_out: bool,
+    /// The system hostname is used if not provided
+    pub hostname: Option<String>,
+    pub live_timer: u32,
+    /// For the stream mappings without their own relayd
+    pub relayd: RelaydEndpoint,
+    /// Packets buffered for each session before the source waits
+    pub channel_capacity: usize,
+    pub stream_mappings: Vec<StreamMapping>,
+    /// How long the packets in flight have to reach relayd on shutdown
+    pub drain_timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SourceConfig {
+    pub source: DeviceOrSocket,
+    pub device_opts: DeviceOpts,
+    pub metadata: MetadataSource,
+    pub clock_offset: Option<ClockOffset>,
+}
+
+impl RelayConfig {
+    /// Loads and validates a config file, relative metadata and console file paths
+    /// are relative to the file
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        Self::parse(&text, path)
+    }
+
+    fn parse(text: &str, path: &Path) -> Result<Self, ConfigError> {
+        let loc = Locator { text, path };
+
+        let file: ConfigFile = toml::from_str(text).map_err(|e| {
+            let (line, col) = e.line_col().map(|(l, c)| (l + 1, c + 1)).unwrap_or((1, 1));
+            ConfigError::Invalid(path.to_owned(), line, col, e.to_string())
+        })?;
+
+        // The top-level source settings are the defaults of the [[sources]]
+        let single_source = file.source.as_ref().map(|url| SourceSection {
+            url: url.clone(),
+            metadata: None,
+            clock_offset: None,
+            device: Default::default(),
+        });
+        if let (Some(url), Some(s)) = (&file.source, file.sources.first()) {
+            return Err(loc.invalid(
+                url.start().min(s.url.start()),
+                "Use either 'source' or [[sources]], not both".to_string(),
+            ));
+        }
+        // Relative paths are relative to the config file
+        let config_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let mut sources = Vec::new();
+        for s in single_source.iter().chain(file.sources.iter()) {
+            let metadata = s
+                .metadata
+                .as_ref()
+                .or(file.metadata.as_ref())
+                .ok_or_else(|| {
+                    loc.invalid(
+                        s.url.start(),
+                        "The source has no metadata, set 'metadata' on it or at the top level"
+                            .to_string(),
+                    )
+                })?;
+            let metadata = match loc.parse(metadata)? {
+                MetadataSource::File(p) if p.is_relative() => {
+                    MetadataSource::File(config_dir.join(p))
+                }
+                m => m,
+            };
+            let mut device_opts = s
+                .device
+                .apply(&loc, file.device.apply(&loc, DeviceOpts::default())?)?;
+            if let Some(ConsoleOutput::File(p)) = &mut device_opts.console {
+                if p.is_relative() {
+                    *p = config_dir.join(&p);
+                }
+            }
+            sources.push(SourceConfig {
+                source: loc.parse(&s.url)?,
+                device_opts,
+                metadata,
+                clock_offset: s
+                    .clock_offset
+                    .as_ref()
+                    .or(file.clock_offset.as_ref())
+                    .map(|v| loc.parse(v))
+                    .transpose()?,
+            });
+        }
+        if sources.is_empty() {
+            return Err(loc.invalid(
+                0,
+                "Missing the source, set 'source' or add [[sources]]".to_string(),
+            ));
+        }
+
+        let mut stream_mappings = Vec::new();
+        for m in file.stream_mappings.iter() {
+            let ranges = |ids: &[Spanned<toml::Value>]| {
+                ids.iter()
+                    .map(|id| id_range(id.get_ref()).map_err(|e| loc.invalid(id.start(), e)))
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            stream_mappings.push(StreamMapping {
+                session_name: m.session_name.get_ref().clone(),
+                pathname: expand_pathname(m.pathname.get_ref()),
+                selector: StreamSelector {
+                    stream_ids: ranges(&m.stream_ids)?,
+                    stream_instance_ids: ranges(&m.stream_instance_ids)?,
+                    sources: m
+                        .sources
+                        .iter()
+                        .map(|v| loc.parse(v))
+                        .collect::<Result<_, _>>()?,
+                },
+                relayds: m.relayds.clone(),
+            });
+        }
+        if stream_mappings.is_empty() {
+            stream_mappings.push(StreamMapping::default());
+        }
+        check_stream_mappings(&stream_mappings, file.fan_out).map_err(|(i, e)| {
+            loc.invalid(file.stream_mappings[i].session_name.start(), e.to_string())
+        })?;
+
+        let channel_capacity = match &file.channel_capacity {
+            Some(c) if *c.get_ref() == 0 => {
+                return Err(loc.invalid(
+                    c.start(),
+                    "The channel capacity must be greater than zero".to_string(),
+                ))
+            }
+            Some(c) => *c.get_ref(),
+            None => DEFAULT_CHANNEL_CAPACITY,
+        };
+
+        Ok(Self {
+            sources,
+            fan_out: file.fan_out,
+            hostname: file.hostname,
+            live_timer: file.live_timer,
+            relayd: file.relayd.unwrap_or_default(),
+            channel_capacity,
+            stream_mappings,
+            drain_timeout: Duration::from_secs(file.drain_timeout),
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    source: Option<Spanned<String>>,
+    #[serde(default)]
+    sources: Vec<SourceSection>,
+    metadata: Option<Spanned<String>>,
+    clock_offset: Option<Spanned<String>>,
+    #[serde(default)]
+    fan_out: bool,
+    hostname: Option<String>,
+    #[serde(default = "default_live_timer")]
+    live_timer: u32,
+    channel_capacity: Option<Spanned<usize>>,
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+    #[serde(default)]
+    device: DeviceSection,
+    relayd: Option<RelaydEndpoint>,
+    #[serde(default, rename = "stream_mapping")]
+    stream_mappings: Vec<StreamMappingSection>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceSection {
+    url: Spanned<String>,
+    metadata: Option<Spanned<String>>,
+    clock_offset: Option<Spanned<String>>,
+    #[serde(default)]
+    device: DeviceSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DeviceSection {
+    baud_rate: Option<u32>,
+    data_bits: Option<Spanned<String>>,
+    flow_control: Option<Spanned<String>>,
+    parity: Option<Spanned<String>>,
+    stop_bits: Option<Spanned<String>>,
+    framing: Option<Spanned<String>>,
+    console: Option<Spanned<String>>,
+    dtr: Option<bool>,
+    rts: Option<bool>,
+    exclusive: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StreamMappingSection {
+    session_name: Spanned<String>,
+    pathname: Spanned<String>,
+    /// IDs or "<first>-<last>" ranges, empty means all
+    #[serde(default)]
+    stream_ids: Vec<Spanned<toml::Value>>,
+    #[serde(default)]
+    stream_instance_ids: Vec<Spanned<toml::Value>>,
+    #[serde(default)]
+    sources: Vec<Spanned<String>>,
+    /// The first one is the primary, the others are best-effort mirrors
+    /// that miss the packets they can't keep up with
+    #[serde(default)]
+    relayds: Vec<RelaydEndpoint>,
+}
+
+impl DeviceSection {
+    /// Overrides the options that are set
+    fn apply(&self, loc: &Locator, mut opts: DeviceOpts) -> Result<DeviceOpts, ConfigError> {
+        if let Some(b) = self.baud_rate {
+            opts.baud_rate = b;
+        }
+        if let Some(v) = &self.data_bits {
+            opts.data_bits = loc.parse(v)?;
+        }
+        if let Some(v) = &self.flow_control {
+            opts.flow_control = loc.parse(v)?;
+        }
+        if let Some(v) = &self.parity {
+            opts.parity = loc.parse(v)?;
+        }
+        if let Some(v) = &self.stop_bits {
+            opts.stop_bits = loc.parse(v)?;
+        }
+        if let Some(v) = &self.framing {
+            opts.framing = loc.parse(v)?;
+        }
+        if let Some(v) = &self.console {
+            opts.console = Some(loc.parse(v)?);
+        }
+        if self.dtr.is_some() {
+            opts.dtr = self.dtr;
+        }
+        if self.rts.is_some() {
+            opts.rts = self.rts;
+        }
+        if self.exclusive.is_some() {
+            opts.exclusive = self.exclusive;
+        }
+        Ok(opts)
+    }
+}
+
+fn default_live_timer() -> u32 {
+    100000
+}
+
+fn default_drain_timeout() -> u64 {
+    DEFAULT_DRAIN_TIMEOUT_SECS
+}
+
+fn id_range(v: &toml::Value) -> Result<RangeInclusive<u64>, String> {
+    match v {
+        toml::Value::Integer(id) => u64::try_from(*id)
+            .map(|id| id..=id)
+            .map_err(|_| format!("Invalid stream ID '{}'", id)),
+        toml::Value::String(s) => parse_range(s),
+        v => Err(format!(
+            "Invalid stream ID '{}', use an integer or a \"<first>-<last>\" range",
+            v
+        )),
+    }
+}
+
+/// Reports errors at their location in the file
+struct Locator<'a> {
+    text: &'a str,
+    path: &'a Path,
+}
+
+impl<'a> Locator<'a> {
+    fn invalid(&self, offset: usize, msg: String) -> ConfigError {
+        let before = &self.text[..offset.min(self.text.len())];
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        ConfigError::Invalid(
+            self.path.to_owned(),
+            before.matches('\n').count() + 1,
+            before[line_start..].chars().count() + 1,
+            msg,
+        )
+    }
+
+    fn parse<T: FromStr<Err = String>>(&self, v: &Spanned<String>) -> Result<T, ConfigError> {
+        v.get_ref().parse().map_err(|e| self.invalid(v.start(), e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::Framing;
+
+    #[test]
+    fn parse_config_file() {
+        let cfg = RelayConfig::parse(
+            r#"
+source = "udp://127.0.0.1:4567"
+metadata = "metadata"
+fan_out = true
+
+[device]
+baud_rate = 921600
+
+[[stream_mapping]]
+session_name = "all"
+pathname = "trace-all"
+
+[[stream_mapping]]
+session_name = "cpus"
+pathname = "trace-cpus"
+stream_ids = ["0-3", 8]
+sources = ["127.0.0.1"]
+relayds = [{ control = "10.0.0.5:5342", data = "10.0.0.5:5343" }]
+"#,
+            Path::new("/etc/relay/relay.toml"),
+        )
+        .unwrap();
+        assert_eq!(cfg.sources.len(), 1);
+        assert_eq!(
+            cfg.sources[0].metadata,
+            MetadataSource::File(PathBuf::from("/etc/relay/metadata"))
+        );
+        assert_eq!(cfg.sources[0].device_opts.baud_rate, 921600);
+        assert_eq!(cfg.sources[0].device_opts.exclusive, None);
+        assert_eq!(cfg.channel_capacity, DEFAULT_CHANNEL_CAPACITY);
+        assert_eq!(cfg.stream_mappings.len(), 2);
+        assert_eq!(
+            cfg.stream_mappings[1].selector.stream_ids,
+            vec![0..=3, 8..=8]
+        );
+        assert_eq!(
+            cfg.stream_mappings[1].relayds[0].data_port,
+            ([10, 0, 0, 5], 5343).into()
+        );
+
+        let cfg = RelayConfig::parse(
+            r#"
+metadata = "in-band"
+clock_offset = "reception"
+[device]
+baud_rate = 9600
+dtr = false
+exclusive = false
+
+[[sources]]
+url = "file:/dev/ttyUSB0"
+
+[[sources]]
+url = "file:/dev/ttyUSB1"
+metadata = "/opt/metadata"
+device = { baud_rate = 921600, framing = "slip", console = "boot.log" }
+"#,
+            Path::new("conf/relay.toml"),
+        )
+        .unwrap();
+        assert_eq!(cfg.sources.len(), 2);
+        assert_eq!(cfg.sources[0].metadata, MetadataSource::InBand);
+        assert_eq!(cfg.sources[0].device_opts.baud_rate, 9600);
+        assert_eq!(cfg.sources[1].device_opts.dtr, Some(false));
+        assert_eq!(cfg.sources[1].device_opts.exclusive, Some(false));
+        assert_eq!(cfg.sources[1].clock_offset, Some(ClockOffset::Reception));
+        assert_eq!(cfg.sources[1].device_opts.baud_rate, 921600);
+        assert_eq!(cfg.sources[1].device_opts.framing, Framing::Slip);
+        assert_eq!(
+            cfg.sources[1].device_opts.console,
+            Some(ConsoleOutput::File("conf/boot.log".into()))
+        );
+
+        let err = RelayConfig::parse(
+            r#"source = "file:/dev/ttyUSB0"
+metadata = "in-band"
+
+[[stream_mapping]]
+session_name = "a"
+pathname = "trace-a"
+stream_ids = [1, 2]
+
+[[stream_mapping]]
+session_name = "b"
+pathname = "trace-b"
+stream_ids = ["2-4"]
+"#,
+            Path::new("relay.toml"),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_, 10, 16, _)), "{}", err);
+
+        let err = RelayConfig::parse(
+            r#"source = "file:/dev/ttyUSB0"
+metadata = "in-band"
+[device]
+parity = "sometimes"
+"#,
+            Path::new("relay.toml"),
+        )
+        .unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_, 4, 10, _)), "{}", err);
+    }
+}
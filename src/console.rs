@@ -0,0 +1,221 @@
+//! Forwarding of the text console sharing a serial line with the CTF packets
+//!
+//! The bytes found between packets are split into lines and written to
+//! stdout, a file, or the clients connected to a TCP port.
+
+use bytes::Bytes;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::{fmt, io};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+/// Bytes chunks buffered between the packet codec and the console writer
+pub const CONSOLE_CHANNEL_CAPACITY: usize = 256;
+
+/// Lines longer than this are split, the console probably isn't text
+const MAX_LINE_LEN: usize = 4096;
+
+/// Lines buffered for each TCP client
+const TCP_CLIENT_CAPACITY: usize = 1024;
+
+/// Where the console lines are written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConsoleOutput {
+    Stdout,
+    /// Appended to
+    File(PathBuf),
+    /// Sent to every client connected to the port
+    Tcp(SocketAddr),
+}
+
+impl fmt::Display for ConsoleOutput {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConsoleOutput::Stdout => f.write_str("-"),
+            ConsoleOutput::File(p) => write!(f, "file:{}", p.display()),
+            ConsoleOutput::Tcp(a) => write!(f, "tcp://{}", a),
+        }
+    }
+}
+
+impl FromStr for ConsoleOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "-" || s == "stdout" {
+            Ok(ConsoleOutput::Stdout)
+        } else if let Some(addr) = s.strip_prefix("tcp://") {
+            addr.parse()
+                .map(ConsoleOutput::Tcp)
+                .map_err(|e| format!("Invalid console TCP address '{}'. {}", addr, e))
+        } else {
+            let path = s.strip_prefix("file:").unwrap_or(s);
+            if path.is_empty() {
+                Err("Invalid console output, use '-', a file path or tcp://<addr>".to_string())
+            } else {
+                Ok(ConsoleOutput::File(path.into()))
+            }
+        }
+    }
+}
+
+/// An opened console output
+pub enum ConsoleSink {
+    Writer(Box<dyn AsyncWrite + Send + Unpin>),
+    /// The lines, and the task accepting the clients
+    Tcp(broadcast::Sender<Bytes>, JoinHandle<()>),
+}
+
+impl Drop for ConsoleSink {
+    fn drop(&mut self) {
+        // Frees the port, the clients see the lines end
+        if let ConsoleSink::Tcp(_, accept) = self {
+            accept.abort();
+        }
+    }
+}
+
+impl ConsoleSink {
+    pub async fn open(output: &ConsoleOutput) -> io::Result<Self> {
+        Ok(match output {
+            ConsoleOutput::Stdout => ConsoleSink::Writer(Box::new(tokio::io::stdout())),
+            ConsoleOutput::File(p) => ConsoleSink::Writer(Box::new(
+                OpenOptions::new().create(true).append(true).open(p).await?,
+            )),
+            ConsoleOutput::Tcp(addr) => {
+                let listener = TcpListener::bind(addr).await?;
+                let (lines, _) = broadcast::channel(TCP_CLIENT_CAPACITY);
+                let accept = tokio::spawn(accept_clients(listener, lines.clone()));
+                ConsoleSink::Tcp(lines, accept)
+            }
+        })
+    }
+
+    /// Writes the console lines until the sender is dropped
+    pub async fn run(mut self, mut bytes: mpsc::Receiver<Bytes>) -> io::Result<()> {
+        let mut splitter = LineSplitter::default();
+        while let Some(b) = bytes.recv().await {
+            for line in splitter.push(&b) {
+                self.write_line(line).await?;
+            }
+        }
+        // The codec is gone, so is the rest of the line
+        if let Some(line) = splitter.flush() {
+            self.write_line(line).await?;
+        }
+        Ok(())
+    }
+
+    async fn write_line(&mut self, line: Bytes) -> io::Result<()> {
+        match self {
+            ConsoleSink::Writer(w) => {
+                w.write_all(&line).await?;
+                w.flush().await?;
+            }
+            ConsoleSink::Tcp(clients, _) => {
+                // No clients connected isn't an error
+                let _ = clients.send(line);
+            }
+        }
+        Ok(())
+    }
+}
+
+async fn accept_clients(listener: TcpListener, lines: broadcast::Sender<Bytes>) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Failed to accept a console client. {}", e);
+                continue;
+            }
+        };
+        debug!("Console client {} connected", peer);
+        let mut rx = lines.subscribe();
+        tokio::spawn(async move {
+            loop {
+                let line = match rx.recv().await {
+                    Ok(l) => l,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        debug!("Console client {} skipped {} lines", peer, n);
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                if stream.write_all(&line).await.is_err() {
+                    break;
+                }
+            }
+            debug!("Console client {} disconnected", peer);
+        });
+    }
+}
+
+/// Splits the console bytes into '\n' terminated lines, dropping '\r's
+#[derive(Default)]
+struct LineSplitter {
+    partial: Vec<u8>,
+}
+
+impl LineSplitter {
+    fn push(&mut self, bytes: &[u8]) -> Vec<Bytes> {
+        let mut lines = Vec::new();
+        for b in bytes.iter().copied() {
+            match b {
+                b'\r' => (),
+                b'\n' => lines.push(self.take_line()),
+                b => {
+                    self.partial.push(b);
+                    if self.partial.len() >= MAX_LINE_LEN {
+                        lines.push(self.take_line());
+                    }
+                }
+            }
+        }
+        lines
+    }
+
+    fn flush(&mut self) -> Option<Bytes> {
+        (!self.partial.is_empty()).then(|| self.take_line())
+    }
+
+    fn take_line(&mut self) -> Bytes {
+        let mut line = String::from_utf8_lossy(&self.partial).into_owned();
+        line.push('\n');
+        self.partial.clear();
+        line.into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn console_lines() {
+        assert_eq!("-".parse(), Ok(ConsoleOutput::Stdout));
+        assert_eq!(
+            "tcp://0.0.0.0:4000".parse(),
+            Ok(ConsoleOutput::Tcp(([0, 0, 0, 0], 4000).into()))
+        );
+        assert_eq!(
+            "file:boot.log".parse(),
+            Ok(ConsoleOutput::File("boot.log".into()))
+        );
+
+        let mut s = LineSplitter::default();
+        assert!(s.push(b"U-Boot 20").is_empty());
+        assert_eq!(
+            s.push(b"22.04\r\n\r\nStarting kernel"),
+            vec![Bytes::from("U-Boot 2022.04\n"), Bytes::from("\n")]
+        );
+        assert_eq!(s.push(b"\n"), vec![Bytes::from("Starting kernel\n")]);
+    }
+}
@@ -1,19 +1,45 @@
 #![deny(warnings, clippy::all)]
 
+use serial::UsbDeviceSelector;
+use std::fmt;
 use std::net::SocketAddr;
 use std::str::FromStr;
+use udp::UdpSource;
 use url::Url;
 
+pub mod config;
+pub mod console;
+pub mod metadata;
 pub mod packet;
 pub mod packet_publisher;
 pub mod packet_subscriber;
 pub mod relayd;
 pub mod serial;
+pub mod stream_mapping;
+pub mod udp;
+
+/// Where a packet was received from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum PacketSource {
+    Device(String),
+    Udp(SocketAddr),
+}
 
 #[derive(Debug, Clone)]
 pub enum DeviceOrSocket {
     Device(String),
-    UdpSocket(SocketAddr),
+    UsbDevice(UsbDeviceSelector),
+    UdpSocket(UdpSource),
+}
+
+impl fmt::Display for DeviceOrSocket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceOrSocket::Device(d) => write!(f, "file:{}", d),
+            DeviceOrSocket::UsbDevice(u) => u.fmt(f),
+            DeviceOrSocket::UdpSocket(u) => u.fmt(f),
+        }
+    }
 }
 
 impl FromStr for DeviceOrSocket {
@@ -23,18 +49,11 @@ impl FromStr for DeviceOrSocket {
         let url = Url::parse(s).map_err(|e| format!("Failed to parse source URL. {}", e))?;
         Ok(match url.scheme() {
             "file" => DeviceOrSocket::Device(url.path().to_string()),
-            "udp" => {
-                let addrs = url
-                    .socket_addrs(|| None)
-                    .map_err(|e| format!("Failed to parse source URL. {}", e))?;
-                if addrs.len() != 1 {
-                    return Err("Source URL contains multiple socket addresses.".to_string());
-                }
-                DeviceOrSocket::UdpSocket(addrs[0])
-            }
+            "serial" => DeviceOrSocket::UsbDevice(url.path().parse()?),
+            "udp" => DeviceOrSocket::UdpSocket(UdpSource::from_url(&url)?),
             s => {
                 return Err(format!(
-                    "Invalid scheme '{}' in source URL. Must be either 'file' or 'udp'.",
+                    "Invalid scheme '{}' in source URL. Must be either 'file', 'serial' or 'udp'.",
                     s
                 ))
             }
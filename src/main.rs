@@ -1,15 +1,26 @@
 #![deny(warnings, clippy::all)]
 
-use chrono::{DateTime, Utc};
-use ctf_packet_relay::packet_publisher::{run_packet_publisher, PacketPublisherConfig};
+use ctf_packet_relay::config::{RelayConfig, SourceConfig};
+use ctf_packet_relay::metadata::{self, ClockOffset, MetadataSet, MetadataSource};
+use ctf_packet_relay::packet_publisher::{
+    run_packet_publisher, MirrorSender, PacketPublisherConfig,
+};
 use ctf_packet_relay::packet_subscriber::{run_packet_subscriber, PacketSubscriberConfig};
 use ctf_packet_relay::serial::DeviceOpts;
+use ctf_packet_relay::stream_mapping::{check_stream_mappings, RelaydEndpoint, StreamMapping};
 use ctf_packet_relay::DeviceOrSocket;
-use std::{collections::BTreeSet, fs, net::SocketAddr, path::PathBuf, str::FromStr, sync::Arc};
+use futures::future::{self, AbortHandle, Aborted};
+use futures::stream::{FuturesUnordered, StreamExt};
+use signals::{Signal, Signals};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::{net::SocketAddr, time::Duration};
 use structopt::{clap, StructOpt};
 use thiserror::Error;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, error};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 
 /// CTF packet relay
 ///
@@ -22,11 +33,13 @@ struct Opts {
     #[structopt(flatten)]
     device_opts: DeviceOpts,
 
-    /// LTTng relayd control address:port
+    /// LTTng relayd control address:port, for the stream mappings
+    /// without their own relayd
     #[structopt(short = "c", long, default_value = "127.0.0.1:5342")]
     control_port: SocketAddr,
 
-    /// LTTng relayd trace data address:port
+    /// LTTng relayd trace data address:port, for the stream mappings
+    /// without their own relayd
     #[structopt(short = "d", long, default_value = "127.0.0.1:5343")]
     data_port: SocketAddr,
 
@@ -39,38 +52,164 @@ struct Opts {
     #[structopt(short = "t", long, name = "duration µs", default_value = "100000")]
     live_timer: u32,
 
-    /// Map stream IDs to a specific LTTng relayd session name and pathname.
+    /// Map streams to a specific LTTng relayd session name and pathname.
     ///
     /// This option can be supplied multiple times.
     ///
     /// The pathname portion can use the keyword $DATETIME as part of its
     /// value, which expands to UTC datetime in the format of YYYYmmdd-HHMMSS.
     ///
-    /// The comma-separated-stream-ids can be set to ANY to match any stream ID.
+    /// The comma-separated stream selectors are any of:
+    ///   - `<id>` or `<first>-<last>`: stream class IDs
+    ///   - `instance=<id>` or `instance=<first>-<last>`: stream instance IDs
+    ///   - `source=<device-path>`, `source=serial:<usb-device>` or
+    ///     `source=<address>[:<port>]`: packet source
+    ///
+    /// A packet must match one selector of each kind that's used.
+    /// The selectors can be set to ANY to match any stream.
+    ///
+    /// The session goes to the global relayd unless the mapping has
+    /// `relayd=<control-address:port>/<data-address:port>` entries.
+    /// The first one is the primary, the others mirror the session.
+    /// Mirrors are best-effort, a failing or lagging mirror is logged and
+    /// misses packets rather than holding up the primary.
     ///
     /// Format:
-    ///   `<session-name>:<pathname>:<comma-separated-stream-ids>`
+    ///   `<session-name>:<pathname>:<comma-separated-stream-selectors>`
     ///
     /// Example:
     ///   --stream-mapping my-stream-a:trace-a:0,1
-    ///   --stream-mapping my-stream-b:trace-b:2,5
+    ///   --stream-mapping my-stream-b:trace-b:2-5
     ///   --stream-mapping session-foo:session-$DATETIME:42
+    ///   --stream-mapping cpu0:trace-cpu0:0,instance=0
+    ///   --stream-mapping modem:trace-modem:ANY,source=10.0.0.2
+    ///   --stream-mapping hw:trace-hw:ANY,relayd=10.0.0.5:5342/10.0.0.5:5343,relayd=10.0.0.6:5342/10.0.0.6:5343
     #[structopt(name = "stream-mapping", short = "s", long, verbatim_doc_comment)]
     stream_mappings: Vec<StreamMapping>,
 
-    /// CTF metadata file path
-    #[structopt(name = "metadata-file")]
-    metadata: PathBuf,
+    /// Deliver each packet to every stream mapping it matches, rather than
+    /// only the first one.
+    ///
+    /// The mappings can then select overlapping streams, e.g. a "full" session
+    /// along with a "focused" one.
+    #[structopt(long)]
+    fan_out: bool,
+
+    /// Clock offset written into the metadata sent to lttng-relayd, so traces
+    /// from targets whose clock starts at zero line up.
+    ///
+    /// Use the keyword `reception` to take the host's time at the reception
+    /// of the first packet as that packet's end timestamp.
+    /// Otherwise, the offset is the clock's origin in seconds from the Unix epoch,
+    /// e.g. computed from a sync event.
+    ///
+    /// Example:
+    ///   --clock-offset reception
+    ///   --clock-offset 1666000000.250000000
+    #[structopt(long, name = "reception|seconds", verbatim_doc_comment)]
+    clock_offset: Option<ClockOffset>,
 
-    /// Source serial device or socket URL
+    /// CTF metadata file path, either plain-text TSDL or packetized
+    ///
+    /// CTF 2 (JSON text sequence) metadata is translated into TSDL, which is
+    /// what lttng-relayd receives.
+    ///
+    /// A directory of metadata files can be given when the targets run different
+    /// firmware, each packet is decoded with the metadata whose trace UUID matches
+    /// the packet header's. Hidden files in the directory are ignored.
+    ///
+    /// The file is watched for modifications. Appended metadata is sent to
+    /// the existing sessions, replaced metadata restarts the streams in
+    /// a new `<pathname>-<N>` directory.
+    ///
+    /// Use the keyword `in-band` to receive the metadata from the source
+    /// itself, as metadata packets preceding the data packets.
+    #[structopt(name = "metadata-file", required_unless = "config")]
+    metadata: Option<MetadataSource>,
+
+    /// Source serial devices or socket URLs
+    ///
+    /// Several sources share the metadata and device options, use a config
+    /// file to set them per source. A failing source doesn't stop the others.
+    ///
+    /// Serial devices are reopened when they're unplugged and come back.
+    /// A USB serial device can be selected by its hexadecimal vendor and
+    /// product IDs and its serial number, rather than its path. The ports of
+    /// multi-port adapters are told apart by their USB interface number.
+    ///
+    /// UDP multicast groups are joined on the 'iface' interface, or one the
+    /// OS picks. Several relays on a host can join the same group.
+    /// Each UDP peer is decoded separately, 'allow' restricts the peers
+    /// to a list of addresses, with or without ports. 'reassembly' joins the
+    /// packets split across datagrams behind a fragment header.
     ///
     /// Examples:
     /// - file:/dev/ttyUSB0
+    /// - file:/dev/serial/by-id/usb-FTDI_FT232R_USB_UART_A10K5QXB-if00-port0
+    /// - serial:vid=0483,pid=5740,serial=ABC123
+    /// - serial:vid=0403,pid=6010,serial=FT1234,interface=01
     /// - udp://localhost:456
-    #[structopt(name = "device-or-socket", verbatim_doc_comment)]
-    source_url: DeviceOrSocket,
+    /// - udp://239.1.2.3:5000?iface=eth1
+    /// - udp://0.0.0.0:5000?allow=10.0.0.2,10.0.0.3:4000
+    /// - udp://0.0.0.0:5000?reassembly&reassembly_timeout=500
+    #[structopt(
+        name = "device-or-socket",
+        required_unless = "config",
+        verbatim_doc_comment
+    )]
+    source_urls: Vec<DeviceOrSocket>,
+
+    /// Packets buffered for each session before reading from the source waits
+    #[structopt(long, default_value = "64")]
+    channel_capacity: usize,
+
+    /// Seconds given on shutdown to the packets already read to reach relayd
+    /// before the streams are closed. SIGINT or SIGTERM starts the shutdown,
+    /// a second one exits right away. SIGHUP reloads the config file.
+    #[structopt(long, name = "seconds", default_value = "5")]
+    drain_timeout: u64,
+
+    /// TOML config file describing the sources, device options, metadata,
+    /// relayd endpoints and stream mappings, instead of the command line options.
+    /// The relay and device options can't be given along with it.
+    ///
+    /// Example:
+    ///   source = "file:/dev/ttyUSB0"
+    ///   metadata = "metadata"
+    ///   channel_capacity = 64
+    ///   [device]
+    ///   baud_rate = 921600
+    ///   [relayd]
+    ///   control = "127.0.0.1:5342"
+    ///   data = "127.0.0.1:5343"
+    ///   [[stream_mapping]]
+    ///   session_name = "system"
+    ///   pathname = "system-$DATETIME"
+    ///   stream_ids = ["0-15", 42]
+    ///   stream_instance_ids = [0]
+    ///   sources = ["10.0.0.2"]
+    ///   relayds = [{ control = "10.0.0.5:5342", data = "10.0.0.5:5343" }]
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "metadata-file", "device-or-socket", "stream-mapping", "reception|seconds", "fan-out",
+            "hostname", "control-port", "data-port", "duration µs", "channel-capacity", "seconds",
+            "baud-rate", "data-bits", "flow-control", "parity", "stop-bits", "framing", "console",
+            "dtr", "rts", "exclusive",
+        ],
+        verbatim_doc_comment
+    )]
+    config: Option<PathBuf>,
 }
 
+#[derive(Debug, Error)]
+#[error("All the sources failed")]
+struct AllSourcesFailedError;
+
+#[derive(Debug, Error)]
+#[error("The packets in flight didn't reach relayd within {0:?}, exiting")]
+struct DrainTimeoutError(Duration);
+
 #[derive(Debug, Error)]
 enum HostnameError {
     #[error("The hostname '{0:?}' contains invalid data")]
@@ -80,16 +219,63 @@ enum HostnameError {
 }
 
 impl Opts {
-    fn hostname(&self) -> Result<String, HostnameError> {
-        if let Some(n) = &self.hostname {
-            Ok(n.clone())
+    fn relay_config(self) -> Result<RelayConfig, Box<dyn std::error::Error>> {
+        if let Some(path) = &self.config {
+            return Ok(RelayConfig::load(path)?);
+        }
+
+        let stream_mappings = if !self.stream_mappings.is_empty() {
+            self.stream_mappings
         } else {
-            let n = hostname::get()?;
-            Ok(n.into_string().map_err(HostnameError::InvalidHostname)?)
+            vec![StreamMapping::default()]
+        };
+        check_stream_mappings(&stream_mappings, self.fan_out).map_err(|(_, e)| e)?;
+
+        if self.channel_capacity == 0 {
+            return Err("The channel capacity must be greater than zero".into());
         }
+
+        // Required without a config file
+        let metadata = self.metadata.ok_or("Missing the metadata")?;
+        let sources = self
+            .source_urls
+            .into_iter()
+            .map(|source| SourceConfig {
+                source,
+                device_opts: self.device_opts.clone(),
+                metadata: metadata.clone(),
+                clock_offset: self.clock_offset,
+            })
+            .collect();
+
+        Ok(RelayConfig {
+            sources,
+            fan_out: self.fan_out,
+            hostname: self.hostname,
+            live_timer: self.live_timer,
+            relayd: RelaydEndpoint {
+                control_port: self.control_port,
+                data_port: self.data_port,
+            },
+            channel_capacity: self.channel_capacity,
+            stream_mappings,
+            drain_timeout: Duration::from_secs(self.drain_timeout),
+        })
     }
 }
 
+fn hostname(hostname: &Option<String>) -> Result<String, HostnameError> {
+    if let Some(n) = hostname {
+        Ok(n.clone())
+    } else {
+        let n = hostname::get()?;
+        Ok(n.into_string().map_err(HostnameError::InvalidHostname)?)
+    }
+}
+
+/// How often the metadata file is checked for modifications
+const METADATA_FILE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     match do_main().await {
@@ -106,115 +292,285 @@ async fn do_main() -> Result<(), Box<dyn std::error::Error>> {
 
     try_init_tracing_subscriber()?;
 
-    let intr = interruptor::Interruptor::new();
-    ctrlc::set_handler(move || {
-        if intr.is_set() {
-            let exit_code = if cfg!(target_family = "unix") {
-                // 128 (fatal error signal "n") + 2 (control-c is fatal error signal 2)
-                130
-            } else {
-                // Windows code 3221225786
-                // -1073741510 == C000013A
-                -1073741510
-            };
-            std::process::exit(exit_code);
-        } else {
-            intr.set();
-        }
-    })?;
-
-    let hostname = opts.hostname()?;
-    let md_bytes = Arc::new(fs::read_to_string(&opts.metadata)?.into_bytes());
-
-    let stream_mappings = if !opts.stream_mappings.is_empty() {
-        opts.stream_mappings
-    } else {
-        vec![StreamMapping::default()]
-    };
+    let mut signals = Signals::new()?;
+    let config_path = opts.config.clone();
+    let mut cfg = opts.relay_config()?;
 
-    // Check that there are no overlapping stream IDs among the stream mappings, must be exclusive
-    // Same for duplicate session names
-    let mut all_stream_ids = BTreeSet::new();
-    let mut all_session_names = BTreeSet::new();
-    for smap in stream_mappings.iter() {
-        for id in smap.stream_ids.iter() {
-            if !all_stream_ids.insert(*id) {
-                return Err(DuplicateStreamIdMappingError(smap.session_name.clone(), *id).into());
+    loop {
+        match run_relay(cfg, config_path.as_deref(), &mut signals).await? {
+            RelayExit::Shutdown => return Ok(()),
+            RelayExit::Reload(new_cfg) => {
+                info!("Restarting with the reloaded configuration");
+                cfg = *new_cfg;
             }
         }
+    }
+}
 
-        if !all_session_names.insert(&smap.session_name) {
-            return Err(DuplicateSessionNameMappingError(smap.session_name.clone()).into());
-        }
+enum RelayExit {
+    Shutdown,
+    /// The packets in flight were drained, the relay restarts with the config
+    Reload(Box<RelayConfig>),
+}
+
+async fn run_relay(
+    cfg: RelayConfig,
+    config_path: Option<&Path>,
+    signals: &mut Signals,
+) -> Result<RelayExit, Box<dyn std::error::Error>> {
+    let hostname = hostname(&cfg.hostname)?;
+    // Sources with the same metadata path share its watcher
+    let mut metadata_watches: Vec<(PathBuf, watch::Receiver<MetadataSet>)> = Vec::new();
+    let mut metadata_updates = Vec::new();
+    for src in cfg.sources.iter() {
+        metadata_updates.push(match &src.metadata {
+            MetadataSource::File(path) => match metadata_watches.iter().find(|(p, _)| p == path) {
+                Some((_, updates_recvr)) => Some(updates_recvr.clone()),
+                None => {
+                    let md = MetadataSet::load(path)?;
+                    let (updates_sender, updates_recvr) = watch::channel(md);
+                    tokio::spawn(metadata::watch_metadata_file(
+                        path.clone(),
+                        METADATA_FILE_POLL_INTERVAL,
+                        updates_sender,
+                    ));
+                    metadata_watches.push((path.clone(), updates_recvr.clone()));
+                    Some(updates_recvr)
+                }
+            },
+            MetadataSource::InBand => None,
+        });
     }
+    // With multiple metadata, sessions start with the metadata of their first packet.
+    // The clock offset changes the metadata
+    let metadata = match metadata_watches.as_slice() {
+        [(_, updates_recvr)]
+            if metadata_updates.iter().all(Option::is_some)
+                && cfg.sources.iter().all(|s| s.clock_offset.is_none()) =>
+        {
+            updates_recvr.borrow().single().cloned()
+        }
+        _ => None,
+    };
 
     let (shutdown_req_sender, shutdown_req_recvr) = broadcast::channel(1);
     let (shutdown_resp_sender, mut shutdown_resp_recvr) = mpsc::channel(1);
+    // The mirrors drain separately, a mirror stuck on its relayd doesn't fail the drain
+    let (mirror_resp_sender, mut mirror_resp_recvr) = mpsc::channel::<()>(1);
 
     let mut pkt_pub_cfgs = Vec::new();
     let mut pkt_sub_cfgs = Vec::new();
-    for s in stream_mappings.into_iter() {
-        let (pkt_pub_sender, pkt_pub_recvr) = mpsc::channel(64);
+    let mut mirror_sub_cfgs = Vec::new();
+    for s in cfg.stream_mappings.into_iter() {
+        let relayds = if s.relayds.is_empty() {
+            vec![cfg.relayd]
+        } else {
+            s.relayds
+        };
+
+        let mut senders = Vec::new();
+        let mut mirrors = Vec::new();
+        for (i, relayd) in relayds.into_iter().enumerate() {
+            let (pkt_pub_sender, pkt_pub_recvr) = mpsc::channel(cfg.channel_capacity);
+            let cfg = PacketSubscriberConfig {
+                control_port: relayd.control_port,
+                data_port: relayd.data_port,
+                hostname: hostname.clone(),
+                session_name: s.session_name.clone(),
+                pathname: s.pathname.clone(),
+                live_timer: cfg.live_timer,
+                metadata: metadata.clone(),
+                packet_receiver: pkt_pub_recvr,
+                shutdown_receiver: shutdown_req_sender.subscribe(),
+                shutdown_responder: if i == 0 {
+                    shutdown_resp_sender.clone()
+                } else {
+                    mirror_resp_sender.clone()
+                },
+            };
+            if i == 0 {
+                senders.push(pkt_pub_sender);
+                pkt_sub_cfgs.push(cfg);
+            } else {
+                let dropped = Arc::new(AtomicU64::new(0));
+                mirrors.push(MirrorSender::new(
+                    format!(
+                        "mirror of session '{}' on relayd {}",
+                        s.session_name, relayd
+                    ),
+                    pkt_pub_sender,
+                    dropped.clone(),
+                ));
+                mirror_sub_cfgs.push((relayd, cfg, dropped));
+            }
+        }
 
+        let sender = senders.remove(0);
         pkt_pub_cfgs.push(PacketPublisherConfig {
-            stream_ids: s.stream_ids,
-            sender: pkt_pub_sender,
+            selector: s.selector,
+            sender,
+            mirrors,
         });
+    }
 
-        pkt_sub_cfgs.push(PacketSubscriberConfig {
-            control_port: opts.control_port,
-            data_port: opts.data_port,
-            hostname: hostname.clone(),
-            session_name: s.session_name,
-            pathname: s.pathname,
-            live_timer: opts.live_timer,
-            metadata_bytes: md_bytes.clone(),
-            packet_receiver: pkt_pub_recvr,
-            shutdown_receiver: shutdown_req_sender.subscribe(),
-            shutdown_responder: shutdown_resp_sender.clone(),
-        })
+    // A mirror's failure is logged, it doesn't take down the primary sessions
+    let mut mirror_subs = Vec::new();
+    for (relayd, cfg, dropped) in mirror_sub_cfgs.into_iter() {
+        let session_name = cfg.session_name.clone();
+        mirror_subs.push(tokio::spawn(async move {
+            if let Err(e) = run_packet_subscriber(cfg).await {
+                warn!(
+                    "Mirror of session '{}' on relayd {} failed. {}",
+                    session_name, relayd, e
+                );
+            }
+            let dropped = dropped.load(Ordering::Relaxed);
+            if dropped != 0 {
+                warn!(
+                    "Mirror of session '{}' on relayd {} missed {} packets",
+                    session_name, relayd, dropped
+                );
+            }
+        }));
     }
 
     let mut pkt_subs_join_handle = tokio::spawn(async move {
         futures::future::try_join_all(pkt_sub_cfgs.into_iter().map(run_packet_subscriber)).await
     });
 
-    let mut pkt_pub_join_handle = tokio::spawn(async move {
-        run_packet_publisher(
-            opts.source_url.clone(),
-            opts.device_opts.clone(),
-            opts.metadata.clone(),
-            pkt_pub_cfgs,
-        )
-        .await
-    });
-
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            debug!("User signaled shutdown");
-        }
-        res = &mut pkt_pub_join_handle => {
-            debug!("Packet publisher returned unexpectedly");
-            match res? {
-                Ok(_) => {},
-                Err(e) => return Err(e),
+    // Each source fails independently
+    let mut pkt_pubs = FuturesUnordered::new();
+    let mut pkt_pub_aborts = Vec::new();
+    for (src, metadata_updates) in cfg.sources.into_iter().zip(metadata_updates) {
+        let channel_configs = pkt_pub_cfgs.clone();
+        let fan_out = cfg.fan_out;
+        let shutdown = shutdown_req_sender.subscribe();
+        let (pkt_pub, abort) = future::abortable(async move {
+            let source = src.source.to_string();
+            let res = run_packet_publisher(
+                src.source,
+                src.device_opts,
+                metadata_updates,
+                src.clock_offset,
+                fan_out,
+                channel_configs,
+                shutdown,
+            )
+            .await;
+            (source, res)
+        });
+        pkt_pub_aborts.push(abort);
+        pkt_pubs.push(tokio::spawn(pkt_pub));
+    }
+    // The subscribers see their channel close once all the sources are gone
+    drop(pkt_pub_cfgs);
+    let pkt_pubs_done = async move {
+        while let Some(res) = pkt_pubs.next().await {
+            match res {
+                Ok(Ok((source, Ok(())))) => debug!("Source {} drained", source),
+                Ok(Ok((source, Err(e)))) => error!("Source {} failed. {}", source, e),
+                Ok(Err(Aborted)) => debug!("Source task aborted"),
+                Err(e) => error!("Source task failed. {}", e),
             }
         }
-        res = &mut pkt_subs_join_handle => {
-            debug!("Packet subscriber returned unexpectedly");
-            match res? {
-                Ok(_) => {},
-                Err(e) => return Err(e),
+    };
+    tokio::pin!(pkt_pubs_done);
+
+    let exit = loop {
+        tokio::select! {
+            sig = signals.recv() => match (sig, config_path) {
+                (Signal::Hangup, Some(path)) => match RelayConfig::load(path) {
+                    Ok(new_cfg) => {
+                        info!("Reloaded the configuration from {}", path.display());
+                        break RelayExit::Reload(Box::new(new_cfg));
+                    }
+                    Err(e) => error!(
+                        "Failed to reload the configuration. {}. Keeping the current configuration",
+                        e
+                    ),
+                },
+                (Signal::Hangup, None) => {
+                    warn!("Received SIGHUP without a config file, there's nothing to reload");
+                }
+                (sig, _) => {
+                    debug!("Received {}, shutting down", sig);
+                    break RelayExit::Shutdown;
+                }
+            },
+            _ = &mut pkt_pubs_done => {
+                debug!("All the packet publishers returned unexpectedly");
+                return Err(AllSourcesFailedError.into());
+            }
+            res = &mut pkt_subs_join_handle => {
+                debug!("Packet subscriber returned unexpectedly");
+                match res? {
+                    Ok(_) => break RelayExit::Shutdown,
+                    Err(e) => return Err(e),
+                }
             }
         }
     };
 
+    // The sources stop reading and decode what they've buffered, the sessions
+    // close their streams once the packets queued for them are sent
+    info!("Draining the packets in flight");
     drop(shutdown_req_recvr);
     drop(shutdown_resp_sender);
-    shutdown_req_sender.send(())?;
-    let _ = shutdown_resp_recvr.recv().await;
+    drop(mirror_resp_sender);
+    let _ = shutdown_req_sender.send(());
+    let deadline = tokio::time::Instant::now() + cfg.drain_timeout;
+    let drained = async move {
+        pkt_pubs_done.await;
+        let _ = shutdown_resp_recvr.recv().await;
+    };
+    let drain = async move {
+        tokio::time::timeout_at(deadline, drained)
+            .await
+            .map_err(|_| DrainTimeoutError(cfg.drain_timeout))?;
+        // Mirrors are best-effort, they get what's left of the drain timeout
+        if tokio::time::timeout_at(deadline, mirror_resp_recvr.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                "The mirrors didn't drain within {:?}, they may be missing packets",
+                cfg.drain_timeout
+            );
+        }
+        Ok::<_, DrainTimeoutError>(())
+    };
+    // A reload waits for the drain too, the new sources reopen the same devices
+    let second_signal = async {
+        loop {
+            match signals.recv().await {
+                Signal::Hangup => debug!("Ignoring SIGHUP while draining"),
+                sig => break sig,
+            }
+        }
+    };
+    tokio::select! {
+        res = drain => match (res, &exit) {
+            (Ok(()), _) => (),
+            (Err(e), RelayExit::Shutdown) => return Err(e.into()),
+            (Err(e), RelayExit::Reload(_)) => warn!(
+                "The packets in flight didn't reach relayd within {:?}, reloading anyway",
+                e.0
+            ),
+        },
+        sig = second_signal => {
+            warn!("Received {} while draining, exiting", sig);
+            std::process::exit(sig.exit_code());
+        }
+    }
 
-    Ok(())
+    // The new sources and sessions mustn't overlap with what's left of these
+    if let RelayExit::Reload(_) = exit {
+        pkt_pub_aborts.iter().for_each(AbortHandle::abort);
+        pkt_subs_join_handle.abort();
+        mirror_subs.iter().for_each(JoinHandle::abort);
+    }
+
+    Ok(exit)
 }
 
 fn try_init_tracing_subscriber() -> Result<(), Box<dyn std::error::Error>> {
@@ -235,132 +591,126 @@ fn try_init_tracing_subscriber() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
-mod interruptor {
-    use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
-    use std::sync::Arc;
-
-    #[derive(Clone, Debug)]
-    #[repr(transparent)]
-    pub struct Interruptor(Arc<AtomicBool>);
-
-    impl Interruptor {
-        pub fn new() -> Self {
-            Interruptor(Arc::new(AtomicBool::new(false)))
-        }
-
-        pub fn set(&self) {
-            self.0.store(true, SeqCst);
-        }
+/// The process signals, handled in one place so that a signal is either
+/// seen by the relay or, while draining, forces the exit
+mod signals {
+    use std::{fmt, io};
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pub enum Signal {
+        /// SIGINT or Ctrl-C
+        Interrupt,
+        /// SIGTERM, e.g. from systemd or docker
+        Terminate,
+        /// SIGHUP
+        Hangup,
+    }
 
-        pub fn is_set(&self) -> bool {
-            self.0.load(SeqCst)
+    impl Signal {
+        pub fn exit_code(self) -> i32 {
+            if cfg!(target_family = "unix") {
+                // 128 + the fatal error signal number
+                128 + match self {
+                    Signal::Hangup => 1,
+                    Signal::Interrupt => 2,
+                    Signal::Terminate => 15,
+                }
+            } else {
+                // Windows code 3221225786
+                // -1073741510 == C000013A
+                -1073741510
+            }
         }
     }
 
-    impl Default for Interruptor {
-        fn default() -> Self {
-            Self::new()
+    impl fmt::Display for Signal {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str(match self {
+                Signal::Interrupt => "SIGINT",
+                Signal::Terminate => "SIGTERM",
+                Signal::Hangup => "SIGHUP",
+            })
         }
     }
-}
 
-#[derive(Debug, Error)]
-#[error("Stream mapping for session '{0}' contains a stream ID ({1}) that is already mapped to another stream")]
-pub struct DuplicateStreamIdMappingError(String, u64);
+    #[cfg(unix)]
+    pub struct Signals {
+        interrupt: tokio::signal::unix::Signal,
+        terminate: tokio::signal::unix::Signal,
+        hangup: tokio::signal::unix::Signal,
+    }
 
-#[derive(Debug, Error)]
-#[error("The session name '{0}' can only be used in a single stream mapping")]
-pub struct DuplicateSessionNameMappingError(String);
-
-#[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd)]
-pub struct StreamMapping {
-    /// Defaults to 'session'
-    pub session_name: String,
-    /// Defaults to 'trace'
-    pub pathname: String,
-    /// Defaults to empty, meaning all stream IDs
-    pub stream_ids: BTreeSet<u64>,
-}
+    #[cfg(unix)]
+    impl Signals {
+        pub fn new() -> io::Result<Self> {
+            use tokio::signal::unix::{signal, SignalKind};
+            Ok(Self {
+                interrupt: signal(SignalKind::interrupt())?,
+                terminate: signal(SignalKind::terminate())?,
+                hangup: signal(SignalKind::hangup())?,
+            })
+        }
 
-impl Default for StreamMapping {
-    fn default() -> Self {
-        Self {
-            session_name: "session".to_string(),
-            pathname: "trace".to_string(),
-            stream_ids: Default::default(),
+        pub async fn recv(&mut self) -> Signal {
+            tokio::select! {
+                _ = self.interrupt.recv() => Signal::Interrupt,
+                _ = self.terminate.recv() => Signal::Terminate,
+                _ = self.hangup.recv() => Signal::Hangup,
+            }
         }
     }
-}
 
-impl FromStr for StreamMapping {
-    type Err = String;
+    #[cfg(not(unix))]
+    pub struct Signals {
+        ctrl_c: tokio::signal::windows::CtrlC,
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let err_msg =
-            "Invalid stream mapping format, use <session-name>:<pathname>:<comma-separated-stream-ids>";
-        let parts: Vec<&str> = s.trim().split(':').filter(|s| !s.is_empty()).collect();
-        if parts.len() != 3 {
-            return Err(err_msg.to_string());
+    #[cfg(not(unix))]
+    impl Signals {
+        pub fn new() -> io::Result<Self> {
+            Ok(Self {
+                ctrl_c: tokio::signal::windows::ctrl_c()?,
+            })
         }
-        let session_name = parts[0].to_string();
-        let pathname_str = parts[1];
-        let ids = parts[2];
-
-        let pathname = if pathname_str.contains("$DATETIME") {
-            let now: DateTime<Utc> = Utc::now();
-            let datetime = now.format("%Y%m%d-%H%M%S").to_string();
-            pathname_str.replace("$DATETIME", &datetime)
-        } else {
-            pathname_str.to_string()
-        };
 
-        Ok(Self {
-            session_name,
-            pathname,
-            stream_ids: if ids == "ANY" {
-                Default::default()
-            } else {
-                ids.split(',')
-                    .filter(|s| !s.is_empty())
-                    .map(|s| s.trim().parse::<u64>())
-                    .collect::<Result<BTreeSet<u64>, _>>()
-                    .map_err(|_| err_msg.to_string())?
-            },
-        })
+        pub async fn recv(&mut self) -> Signal {
+            self.ctrl_c.recv().await;
+            Signal::Interrupt
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use chrono::TimeZone;
 
     #[test]
-    fn stream_mappings() {
-        assert_eq!(
-            StreamMapping::from_str("my-stream-a:trace-a:0,1,22,44").unwrap(),
-            StreamMapping {
-                session_name: "my-stream-a".to_owned(),
-                pathname: "trace-a".to_owned(),
-                stream_ids: vec![0, 1, 22, 44].into_iter().collect(),
-            }
-        );
-
-        assert_eq!(
-            StreamMapping::from_str("my-stream-a:trace-a:ANY").unwrap(),
-            StreamMapping {
-                session_name: "my-stream-a".to_owned(),
-                pathname: "trace-a".to_owned(),
-                stream_ids: Default::default(),
-            }
-        );
-
-        let sm = StreamMapping::from_str("system-session:system=$DATETIME:1, 2, 4").unwrap();
-        assert_eq!(sm.session_name, "system-session".to_owned());
-        assert_eq!(sm.stream_ids, vec![1, 2, 4].into_iter().collect());
-        let parts: Vec<&str> = sm.pathname.split('=').collect();
-        assert_eq!(parts.len(), 2);
-        assert_eq!(parts[0], "system");
-        assert!(Utc.datetime_from_str(parts[1], "%Y%m%d-%H%M%S").is_ok());
+    fn config_file_conflicts_with_relay_options() {
+        let opts = Opts::from_iter_safe(["ctf-packet-relay", "--config", "relay.toml"]).unwrap();
+        assert_eq!(opts.config, Some(PathBuf::from("relay.toml")));
+
+        for args in [
+            &["-c", "127.0.0.1:6342"][..],
+            &["--data-port", "127.0.0.1:6343"],
+            &["--live-timer", "1000"],
+            &["--channel-capacity", "8"],
+            &["--drain-timeout", "1"],
+            &["--baud-rate", "9600"],
+            &["--framing", "slip"],
+            &["--console", "-"],
+            &["--dtr", "off"],
+            &["--exclusive", "off"],
+            &["--fan-out"],
+            &["/dev/ttyUSB0"],
+        ] {
+            let err = Opts::from_iter_safe(
+                ["ctf-packet-relay", "--config", "relay.toml"]
+                    .iter()
+                    .chain(args),
+            )
+            .unwrap_err();
+            assert_eq!(err.kind, clap::ErrorKind::ArgumentConflict, "{:?}", args);
+        }
     }
 }
@@ -0,0 +1,720 @@
+//! CTF 2 metadata
+//!
+//! CTF 2 metadata is a JSON text sequence (RFC 7464) of fragments.
+//! Neither the packet decoder nor lttng-relayd understand it, so it's
+//! translated into the equivalent TSDL.
+//!
+//! CTF 2 gives meaning to fields through roles, TSDL through field names:
+//! members with a role are renamed to the TSDL name of that role
+//! (e.g. `packet-total-length` becomes `packet_size`).
+//!
+//! Fragments are translated in order, appending fragments (e.g. new event
+//! record classes) to a metadata appends to its TSDL translation.
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt::Write;
+use thiserror::Error;
+use tracing::debug;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+pub enum Ctf2Error {
+    #[error("CTF 2 metadata fragment {0} is not valid JSON. {1}")]
+    Json(usize, serde_json::Error),
+
+    #[error("CTF 2 metadata fragment {0} is invalid. {1}")]
+    Fragment(usize, String),
+
+    #[error("CTF 2 metadata must start with a preamble fragment")]
+    MissingPreamble,
+}
+
+/// RFC 7464 record separator, precedes each fragment
+const RECORD_SEPARATOR: u8 = 0x1E;
+
+pub(crate) fn is_ctf2(bytes: &[u8]) -> bool {
+    bytes
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map(|b| *b == RECORD_SEPARATOR)
+        .unwrap_or(false)
+}
+
+/// Complete CTF 2 metadata starts with a preamble fragment,
+/// appended fragments don't
+pub(crate) fn has_preamble(bytes: &[u8]) -> bool {
+    fragments(bytes)
+        .next()
+        .and_then(|f| serde_json::from_slice::<Value>(f).ok())
+        .map(|f| f["type"] == "preamble")
+        .unwrap_or(false)
+}
+
+fn fragments(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes
+        .split(|b| *b == RECORD_SEPARATOR)
+        .filter(|f| f.iter().any(|b| !b.is_ascii_whitespace()))
+}
+
+/// Translate CTF 2 metadata into TSDL
+pub(crate) fn to_tsdl(bytes: &[u8]) -> std::result::Result<String, Ctf2Error> {
+    let fragments = fragments(bytes)
+        .enumerate()
+        .map(|(idx, f)| serde_json::from_slice::<Value>(f).map_err(|e| Ctf2Error::Json(idx, e)))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    match fragments.first() {
+        Some(f) if f["type"] == "preamble" => (),
+        _ => return Err(Ctf2Error::MissingPreamble),
+    }
+
+    let mut t = Translator::default();
+    for f in fragments.iter() {
+        collect_selectors(f, &mut t.selectors);
+    }
+    for (idx, f) in fragments.iter().enumerate() {
+        t.fragment(f).map_err(|e| Ctf2Error::Fragment(idx, e))?;
+    }
+    Ok(t.tsdl)
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Scope {
+    PacketHeader,
+    PacketContext,
+    EventHeader,
+    Other,
+}
+
+#[derive(Default)]
+struct Translator {
+    tsdl: String,
+    trace_uuid: Option<Uuid>,
+    aliases: HashMap<String, Value>,
+    /// Default clock class of each data stream class
+    stream_clocks: HashMap<u64, String>,
+    /// Variant options by selector member name, TSDL variants need an enum selector
+    /// whose labels are the option names
+    selectors: HashMap<String, Vec<(String, Value)>>,
+    /// Members renamed after their role, field locations follow the renames
+    renames: HashMap<String, String>,
+}
+
+type Result<T> = std::result::Result<T, String>;
+
+impl Translator {
+    fn fragment(&mut self, f: &Value) -> Result<()> {
+        let ty = f["type"].as_str().ok_or("missing 'type'")?;
+        match ty {
+            "preamble" => self.preamble(f),
+            "field-class-alias" => {
+                let name = str_prop(f, "name")?.ok_or("missing 'name'")?;
+                let fc = f.get("field-class").ok_or("missing 'field-class'")?;
+                self.aliases.insert(name.to_string(), fc.clone());
+                Ok(())
+            }
+            "trace-class" => self.trace_class(f),
+            "clock-class" => self.clock_class(f),
+            "data-stream-class" => self.data_stream_class(f),
+            "event-record-class" => self.event_record_class(f),
+            _ => {
+                debug!("Ignoring CTF 2 fragment of type '{}'", ty);
+                Ok(())
+            }
+        }
+    }
+
+    fn preamble(&mut self, f: &Value) -> Result<()> {
+        match f["version"].as_u64() {
+            Some(2) => (),
+            v => return Err(format!("unsupported version {:?}", v)),
+        }
+        self.trace_uuid = f.get("uuid").map(uuid_prop).transpose()?;
+        self.tsdl
+            .push_str("/* CTF 1.8 */\n\n/* Translated from CTF 2 metadata */\n\n");
+        Ok(())
+    }
+
+    fn trace_class(&mut self, f: &Value) -> Result<()> {
+        let header = f.get("packet-header-field-class");
+        let byte_order = header
+            .map(|h| self.first_byte_order(h))
+            .transpose()?
+            .flatten()
+            .unwrap_or("le");
+        let mut s = String::from("trace {\n\tmajor = 1;\n\tminor = 8;\n");
+        if let Some(uuid) = self.trace_uuid {
+            let _ = writeln!(s, "\tuuid = \"{}\";", uuid);
+        }
+        let _ = writeln!(s, "\tbyte_order = {};", byte_order);
+        if let Some(h) = header {
+            let _ = writeln!(
+                s,
+                "\tpacket.header := {};",
+                self.scope_struct(h, Scope::PacketHeader, None)?
+            );
+        }
+        s.push_str("};\n\n");
+
+        if let Some(env) = f.get("environment").and_then(Value::as_object) {
+            s.push_str("env {\n");
+            for (k, v) in env.iter() {
+                let v = match v {
+                    Value::String(v) => quote(v),
+                    Value::Number(n) if n.is_i64() || n.is_u64() => n.to_string(),
+                    _ => continue,
+                };
+                let _ = writeln!(s, "\t{} = {};", identifier(k), v);
+            }
+            s.push_str("};\n\n");
+        }
+        self.tsdl.push_str(&s);
+        Ok(())
+    }
+
+    fn clock_class(&mut self, f: &Value) -> Result<()> {
+        // 'name' in earlier drafts of the specification
+        let name = str_prop(f, "id")?
+            .or(str_prop(f, "name")?)
+            .ok_or("missing 'id'")?;
+        let freq = f["frequency"].as_u64().ok_or("missing 'frequency'")?;
+        let offset = f.get("offset-from-origin").or(f.get("offset"));
+        let mut s = String::from("clock {\n");
+        let _ = writeln!(s, "\tname = {};", identifier(name));
+        if let Some(uuid) = f.get("uuid").map(uuid_prop).transpose()? {
+            let _ = writeln!(s, "\tuuid = \"{}\";", uuid);
+        }
+        if let Some(desc) = str_prop(f, "description")? {
+            let _ = writeln!(s, "\tdescription = {};", quote(desc));
+        }
+        let _ = writeln!(s, "\tfreq = {};", freq);
+        if let Some(precision) = f["precision"].as_u64() {
+            let _ = writeln!(s, "\tprecision = {};", precision);
+        }
+        if let Some(secs) = offset.and_then(|o| o["seconds"].as_i64()) {
+            let _ = writeln!(s, "\toffset_s = {};", secs);
+        }
+        if let Some(cycles) = offset.and_then(|o| o["cycles"].as_u64()) {
+            let _ = writeln!(s, "\toffset = {};", cycles);
+        }
+        if f["origin"] == "unix-epoch" {
+            s.push_str("\tabsolute = TRUE;\n");
+        }
+        s.push_str("};\n\n");
+        self.tsdl.push_str(&s);
+        Ok(())
+    }
+
+    fn data_stream_class(&mut self, f: &Value) -> Result<()> {
+        let id = f["id"].as_u64().unwrap_or(0);
+        let clock = str_prop(f, "default-clock-class-id")?
+            .or(str_prop(f, "default-clock-class-name")?)
+            .map(identifier);
+        if let Some(c) = &clock {
+            self.stream_clocks.insert(id, c.clone());
+        }
+        let mut s = String::from("stream {\n");
+        let _ = writeln!(s, "\tid = {};", id);
+        let scopes = [
+            (
+                "packet-context-field-class",
+                "packet.context",
+                Scope::PacketContext,
+            ),
+            (
+                "event-record-header-field-class",
+                "event.header",
+                Scope::EventHeader,
+            ),
+            (
+                "event-record-common-context-field-class",
+                "event.context",
+                Scope::Other,
+            ),
+        ];
+        for (prop, tsdl_scope, scope) in scopes.into_iter() {
+            if let Some(fc) = f.get(prop) {
+                let st = self.scope_struct(fc, scope, clock.as_deref())?;
+                let _ = writeln!(s, "\t{} := {};", tsdl_scope, st);
+            }
+        }
+        s.push_str("};\n\n");
+        self.tsdl.push_str(&s);
+        Ok(())
+    }
+
+    fn event_record_class(&mut self, f: &Value) -> Result<()> {
+        let id = f["id"].as_u64().unwrap_or(0);
+        let stream_id = f["data-stream-class-id"].as_u64().unwrap_or(0);
+        let clock = self.stream_clocks.get(&stream_id).cloned();
+        let mut s = String::from("event {\n");
+        if let Some(name) = str_prop(f, "name")? {
+            let _ = writeln!(s, "\tname = {};", quote(name));
+        }
+        let _ = writeln!(s, "\tid = {};", id);
+        let _ = writeln!(s, "\tstream_id = {};", stream_id);
+        let scopes = [
+            ("specific-context-field-class", "context"),
+            ("payload-field-class", "fields"),
+        ];
+        for (prop, tsdl_scope) in scopes.into_iter() {
+            if let Some(fc) = f.get(prop) {
+                let st = self.scope_struct(fc, Scope::Other, clock.as_deref())?;
+                let _ = writeln!(s, "\t{} := {};", tsdl_scope, st);
+            }
+        }
+        s.push_str("};\n\n");
+        self.tsdl.push_str(&s);
+        Ok(())
+    }
+
+    fn scope_struct(&mut self, fc: &Value, scope: Scope, clock: Option<&str>) -> Result<String> {
+        let fc = self.resolve(fc)?;
+        if fc["type"] != "structure" {
+            return Err("scope field classes must be structures".into());
+        }
+        let (spec, _) = self.field_class(&fc, "", scope, clock, 1)?;
+        Ok(spec)
+    }
+
+    fn resolve(&self, fc: &Value) -> Result<Value> {
+        match fc {
+            Value::String(alias) => self
+                .aliases
+                .get(alias)
+                .cloned()
+                .ok_or_else(|| format!("unknown field class alias '{}'", alias)),
+            Value::Object(_) => Ok(fc.clone()),
+            _ => Err("field classes must be objects or alias names".into()),
+        }
+    }
+
+    /// TSDL type specifier and declarator suffix (array lengths) of a member
+    fn field_class(
+        &mut self,
+        fc: &Value,
+        member_name: &str,
+        scope: Scope,
+        clock: Option<&str>,
+        depth: usize,
+    ) -> Result<(String, String)> {
+        let fc = self.resolve(fc)?;
+        let ty = fc["type"].as_str().ok_or("field class is missing 'type'")?;
+        let spec = match ty {
+            "fixed-length-unsigned-integer"
+            | "fixed-length-signed-integer"
+            | "fixed-length-bit-array"
+            | "fixed-length-bit-map"
+            | "fixed-length-boolean" => {
+                let signed = ty == "fixed-length-signed-integer";
+                let int = integer(&fc, signed, role_clock(&fc, scope, clock))?;
+                let labels = match fc.get("mappings").and_then(Value::as_object) {
+                    Some(m) => enum_labels(m.iter().map(|(k, v)| (k.as_str(), v)))?,
+                    None => match self.selectors.get(member_name) {
+                        Some(opts) => enum_labels(opts.iter().map(|(k, v)| (k.as_str(), v)))?,
+                        None => Vec::new(),
+                    },
+                };
+                if labels.is_empty() {
+                    int
+                } else {
+                    let indent = "\t".repeat(depth + 1);
+                    let mut s = format!("enum : {} {{\n", int);
+                    for l in labels.iter() {
+                        let _ = writeln!(s, "{}{},", indent, l);
+                    }
+                    let _ = write!(s, "{}}}", "\t".repeat(depth));
+                    s
+                }
+            }
+            "fixed-length-floating-point-number" => {
+                let (exp_dig, mant_dig) = match fc["length"].as_u64() {
+                    Some(32) => (8, 24),
+                    Some(64) => (11, 53),
+                    l => return Err(format!("unsupported floating point number length {:?}", l)),
+                };
+                format!(
+                    "floating_point {{ exp_dig = {}; mant_dig = {}; byte_order = {}; align = {}; }}",
+                    exp_dig,
+                    mant_dig,
+                    byte_order(&fc)?,
+                    fc["alignment"].as_u64().unwrap_or(1)
+                )
+            }
+            "null-terminated-string" => "string".to_string(),
+            "static-length-string" | "static-length-blob" => {
+                let len = fc["length"].as_u64().ok_or("missing 'length'")?;
+                return Ok((byte_type(ty).to_string(), format!("[{}]", len)));
+            }
+            "dynamic-length-string" | "dynamic-length-blob" => {
+                let len = self.location(&fc["length-field-location"])?;
+                return Ok((byte_type(ty).to_string(), format!("[{}]", len)));
+            }
+            "static-length-array" => {
+                let len = fc["length"].as_u64().ok_or("missing 'length'")?;
+                let elem = fc
+                    .get("element-field-class")
+                    .ok_or("missing 'element-field-class'")?;
+                let (spec, suffix) = self.field_class(elem, "", scope, clock, depth)?;
+                return Ok((spec, format!("[{}]{}", len, suffix)));
+            }
+            "dynamic-length-array" => {
+                let len = self.location(&fc["length-field-location"])?;
+                let elem = fc
+                    .get("element-field-class")
+                    .ok_or("missing 'element-field-class'")?;
+                let (spec, suffix) = self.field_class(elem, "", scope, clock, depth)?;
+                return Ok((spec, format!("[{}]{}", len, suffix)));
+            }
+            "structure" => {
+                let mut s = String::from("struct {\n");
+                let members = fc["member-classes"].as_array().cloned().unwrap_or_default();
+                for m in members.iter() {
+                    let name = str_prop(m, "name")?.ok_or("member class is missing 'name'")?;
+                    let mfc = self.resolve(m.get("field-class").ok_or("missing 'field-class'")?)?;
+                    let tsdl_name = match role_name(&mfc, scope) {
+                        Some(role_name) => {
+                            self.renames.insert(name.to_string(), role_name.to_string());
+                            role_name.to_string()
+                        }
+                        None => identifier(name),
+                    };
+                    let (spec, suffix) = self.field_class(&mfc, name, scope, clock, depth + 1)?;
+                    let _ = writeln!(
+                        s,
+                        "{}{} {}{};",
+                        "\t".repeat(depth + 1),
+                        spec,
+                        tsdl_name,
+                        suffix
+                    );
+                }
+                let _ = write!(s, "{}}}", "\t".repeat(depth));
+                if let Some(align) = fc["minimum-alignment"].as_u64() {
+                    let _ = write!(s, " align({})", align);
+                }
+                s
+            }
+            "variant" => {
+                let tag = self.location(&fc["selector-field-location"])?;
+                let mut s = format!("variant <{}> {{\n", tag);
+                let options = fc["options"].as_array().cloned().unwrap_or_default();
+                for o in options.iter() {
+                    let name = str_prop(o, "name")?.ok_or("variant options must have a name")?;
+                    let ofc = o.get("field-class").ok_or("missing 'field-class'")?;
+                    let (spec, suffix) = self.field_class(ofc, name, scope, clock, depth + 1)?;
+                    let _ = writeln!(
+                        s,
+                        "{}{} {}{};",
+                        "\t".repeat(depth + 1),
+                        spec,
+                        identifier(name),
+                        suffix
+                    );
+                }
+                let _ = write!(s, "{}}}", "\t".repeat(depth));
+                s
+            }
+            _ => return Err(format!("field class type '{}' has no TSDL equivalent", ty)),
+        };
+        Ok((spec, String::new()))
+    }
+
+    /// TSDL path of a field location
+    fn location(&self, loc: &Value) -> Result<String> {
+        let path = loc["path"]
+            .as_array()
+            .ok_or("field location is missing 'path'")?;
+        let names: Vec<String> = path
+            .iter()
+            .filter_map(Value::as_str)
+            .map(|n| {
+                self.renames
+                    .get(n)
+                    .cloned()
+                    .unwrap_or_else(|| identifier(n))
+            })
+            .collect();
+        let prefix = match loc["origin"].as_str() {
+            Some("packet-header") => "trace.packet.header.",
+            Some("packet-context") => "stream.packet.context.",
+            Some("event-record-header") => "stream.event.header.",
+            Some("event-record-common-context") => "stream.event.context.",
+            Some("event-record-specific-context") => "event.context.",
+            Some("event-record-payload") => "event.fields.",
+            Some(o) => return Err(format!("unknown field location origin '{}'", o)),
+            // Relative to the enclosing structures, TSDL looks up names the same way
+            None if path.iter().any(Value::is_null) => {
+                return names
+                    .last()
+                    .cloned()
+                    .ok_or_else(|| "empty field location".into())
+            }
+            None => "",
+        };
+        Ok(format!("{}{}", prefix, names.join(".")))
+    }
+
+    /// Byte order of the first fixed-length field, the packet magic number's
+    fn first_byte_order(&self, fc: &Value) -> Result<Option<&'static str>> {
+        let fc = self.resolve(fc)?;
+        if fc.get("byte-order").is_some() {
+            return byte_order(&fc).map(Some);
+        }
+        for m in fc["member-classes"].as_array().into_iter().flatten() {
+            if let Some(fc) = m.get("field-class") {
+                if let Some(bo) = self.first_byte_order(fc)? {
+                    return Ok(Some(bo));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn collect_selectors(v: &Value, selectors: &mut HashMap<String, Vec<(String, Value)>>) {
+    match v {
+        Value::Object(o) => {
+            if o.get("type").map(|t| t == "variant").unwrap_or(false) {
+                let selector = o
+                    .get("selector-field-location")
+                    .and_then(|l| l["path"].as_array())
+                    .and_then(|p| p.iter().rev().find_map(Value::as_str));
+                if let Some(selector) = selector {
+                    let opts = selectors.entry(selector.to_string()).or_default();
+                    for opt in o
+                        .get("options")
+                        .and_then(Value::as_array)
+                        .into_iter()
+                        .flatten()
+                    {
+                        if let (Some(name), Some(ranges)) =
+                            (opt["name"].as_str(), opt.get("selector-field-ranges"))
+                        {
+                            opts.push((name.to_string(), ranges.clone()));
+                        }
+                    }
+                }
+            }
+            o.values().for_each(|v| collect_selectors(v, selectors));
+        }
+        Value::Array(a) => a.iter().for_each(|v| collect_selectors(v, selectors)),
+        _ => (),
+    }
+}
+
+fn integer(fc: &Value, signed: bool, clock: Option<&str>) -> Result<String> {
+    let size = fc["length"].as_u64().ok_or("missing 'length'")?;
+    let mut s = format!(
+        "integer {{ size = {}; align = {}; signed = {}; byte_order = {};",
+        size,
+        fc["alignment"].as_u64().unwrap_or(1),
+        signed,
+        byte_order(fc)?
+    );
+    if let Some(base) = fc["preferred-display-base"].as_u64() {
+        let _ = write!(s, " base = {};", base);
+    }
+    if let Some(clock) = clock {
+        let _ = write!(s, " map = clock.{}.value;", clock);
+    }
+    s.push_str(" }");
+    Ok(s)
+}
+
+fn byte_order(fc: &Value) -> Result<&'static str> {
+    match fc["byte-order"].as_str() {
+        Some("little-endian") => Ok("le"),
+        Some("big-endian") => Ok("be"),
+        o => Err(format!("unsupported byte order {:?}", o)),
+    }
+}
+
+fn byte_type(ty: &str) -> &'static str {
+    if ty.ends_with("string") {
+        "integer { size = 8; align = 8; signed = false; encoding = UTF8; }"
+    } else {
+        "integer { size = 8; align = 8; signed = false; }"
+    }
+}
+
+/// `"label" = lower ... upper` entries of each range
+fn enum_labels<'a, I: Iterator<Item = (&'a str, &'a Value)>>(mappings: I) -> Result<Vec<String>> {
+    let mut labels = Vec::new();
+    for (label, ranges) in mappings {
+        for r in ranges
+            .as_array()
+            .ok_or("integer ranges must be arrays")?
+            .iter()
+        {
+            match r.as_array().map(Vec::as_slice) {
+                Some([lower, upper]) if lower == upper => {
+                    labels.push(format!("{} = {}", quote(label), lower))
+                }
+                Some([lower, upper]) => {
+                    labels.push(format!("{} = {} ... {}", quote(label), lower, upper))
+                }
+                _ => return Err("integer ranges must have a lower and upper value".into()),
+            }
+        }
+    }
+    Ok(labels)
+}
+
+fn roles(fc: &Value) -> impl Iterator<Item = &str> {
+    fc.get("roles")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+}
+
+/// TSDL field name with the same meaning as the member's role
+fn role_name(fc: &Value, scope: Scope) -> Option<&'static str> {
+    roles(fc).find_map(|role| match (scope, role) {
+        (Scope::PacketHeader, "packet-magic-number") => Some("magic"),
+        (Scope::PacketHeader, "metadata-stream-uuid") => Some("uuid"),
+        (Scope::PacketHeader, "data-stream-class-id") => Some("stream_id"),
+        (Scope::PacketHeader, "data-stream-id") => Some("stream_instance_id"),
+        (Scope::PacketContext, "packet-total-length") => Some("packet_size"),
+        (Scope::PacketContext, "packet-content-length") => Some("content_size"),
+        (Scope::PacketContext, "default-clock-timestamp") => Some("timestamp_begin"),
+        (Scope::PacketContext, "packet-end-default-clock-timestamp") => Some("timestamp_end"),
+        (Scope::PacketContext, "discarded-event-record-counter-snapshot") => {
+            Some("events_discarded")
+        }
+        (Scope::PacketContext, "packet-sequence-number") => Some("packet_seq_num"),
+        (Scope::EventHeader, "event-record-class-id") => Some("id"),
+        (Scope::EventHeader, "default-clock-timestamp") => Some("timestamp"),
+        _ => None,
+    })
+}
+
+/// Clock to map the integer to, for timestamp roles
+fn role_clock<'a>(fc: &Value, scope: Scope, clock: Option<&'a str>) -> Option<&'a str> {
+    let is_timestamp = roles(fc).any(|role| {
+        matches!(
+            (scope, role),
+            (
+                Scope::PacketContext,
+                "default-clock-timestamp" | "packet-end-default-clock-timestamp"
+            ) | (Scope::EventHeader, "default-clock-timestamp")
+        )
+    });
+    clock.filter(|_| is_timestamp)
+}
+
+fn str_prop<'a>(v: &'a Value, key: &str) -> Result<Option<&'a str>> {
+    match v.get(key) {
+        None | Some(Value::Null) => Ok(None),
+        Some(Value::String(s)) => Ok(Some(s)),
+        Some(_) => Err(format!("'{}' must be a string", key)),
+    }
+}
+
+fn uuid_prop(v: &Value) -> Result<Uuid> {
+    let bytes = v
+        .as_array()
+        .filter(|a| a.len() == 16)
+        .and_then(|a| {
+            a.iter()
+                .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+                .collect::<Option<Vec<u8>>>()
+        })
+        .ok_or("UUIDs must be arrays of 16 bytes")?;
+    Uuid::from_slice(&bytes).map_err(|e| e.to_string())
+}
+
+/// TSDL identifiers can't have dashes and the like
+fn identifier(name: &str) -> String {
+    let mut id: String = name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if id.starts_with(|c: char| c.is_ascii_digit()) || id.is_empty() {
+        id.insert(0, '_');
+    }
+    id
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tsdl::Schema;
+
+    const UUID: [u8; 16] = [
+        0x2a, 0x6c, 0x8e, 0x4f, 0x5b, 0x1d, 0x4c, 0x3e, 0x9a, 0x7b, 0x0c, 0x1d, 0x2e, 0x3f, 0x40,
+        0x51,
+    ];
+
+    fn ctf2() -> Vec<u8> {
+        let fragments = [
+            serde_json::json!({"type": "preamble", "version": 2, "uuid": UUID}),
+            serde_json::json!({"type": "field-class-alias", "name": "u32", "field-class": {
+                "type": "fixed-length-unsigned-integer", "length": 32, "byte-order": "little-endian", "alignment": 8
+            }}),
+            serde_json::json!({"type": "trace-class", "packet-header-field-class": {
+            "type": "structure", "member-classes": [
+                {"name": "the-magic", "field-class": {
+                    "type": "fixed-length-unsigned-integer", "length": 32, "byte-order": "little-endian",
+                    "alignment": 8, "roles": ["packet-magic-number"]}},
+                {"name": "the-uuid", "field-class": {
+                    "type": "static-length-blob", "length": 16, "roles": ["metadata-stream-uuid"]}},
+                {"name": "dsc", "field-class": {
+                    "type": "fixed-length-unsigned-integer", "length": 8, "byte-order": "little-endian",
+                    "alignment": 8, "roles": ["data-stream-class-id"]}}
+            ]}}),
+            serde_json::json!({"type": "clock-class", "id": "cpu-clock", "frequency": 1_000_000_000u64,
+                "origin": "unix-epoch", "offset-from-origin": {"seconds": 10, "cycles": 5}}),
+            serde_json::json!({"type": "data-stream-class", "id": 1, "default-clock-class-id": "cpu-clock",
+            "packet-context-field-class": {"type": "structure", "member-classes": [
+                {"name": "len", "field-class": {
+                    "type": "fixed-length-unsigned-integer", "length": 32, "byte-order": "little-endian",
+                    "alignment": 8, "roles": ["packet-total-length"]}},
+                {"name": "begin", "field-class": {
+                    "type": "fixed-length-unsigned-integer", "length": 64, "byte-order": "little-endian",
+                    "alignment": 8, "roles": ["default-clock-timestamp"]}}
+            ]}}),
+            serde_json::json!({"type": "event-record-class", "id": 3, "data-stream-class-id": 1, "name": "ev",
+            "payload-field-class": {"type": "structure", "member-classes": [
+                {"name": "n", "field-class": "u32"},
+                {"name": "vals", "field-class": {"type": "dynamic-length-array",
+                    "length-field-location": {"origin": "event-record-payload", "path": ["n"]},
+                    "element-field-class": "u32"}},
+                {"name": "msg", "field-class": {"type": "null-terminated-string"}}
+            ]}}),
+        ];
+        let mut bytes = Vec::new();
+        for f in fragments.iter() {
+            bytes.push(RECORD_SEPARATOR);
+            bytes.extend_from_slice(f.to_string().as_bytes());
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    #[test]
+    fn translate_to_tsdl() {
+        let bytes = ctf2();
+        assert!(is_ctf2(&bytes));
+        assert!(has_preamble(&bytes));
+        let tsdl = to_tsdl(&bytes).unwrap();
+        assert!(tsdl.starts_with("/* CTF 1.8 */"));
+        assert!(tsdl.contains("packet_size;"));
+        assert!(tsdl.contains("map = clock.cpu_clock.value; } timestamp_begin;"));
+        assert!(tsdl.contains(" vals[event.fields.n];"));
+
+        let schema = Schema::parse(&tsdl).unwrap();
+        assert_eq!(schema.trace.uuid, Some(Uuid::from_bytes(UUID)));
+        assert_eq!(schema.packet_header_uuid_offset(), Some(4));
+
+        assert!(matches!(
+            to_tsdl(&bytes[bytes.iter().position(|b| *b == b'\n').unwrap() + 1..]),
+            Err(Ctf2Error::MissingPreamble)
+        ));
+    }
+}
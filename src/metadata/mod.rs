@@ -0,0 +1,465 @@
+//! CTF metadata handling
+//!
+//! The relay works with plain-text TSDL internally, it's what both
+//! the packet decoder and lttng-relayd expect.
+//! Packetized metadata is unpacked when loaded, CTF 2 metadata is
+//! translated into TSDL.
+
+use bytes::Bytes;
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use std::{fs, io, process, str};
+use thiserror::Error;
+use tokio::sync::watch;
+use tracing::{debug, info, warn};
+use tsdl::Schema;
+use uuid::Uuid;
+
+pub use ctf2::Ctf2Error;
+pub use packetized::PacketizedMetadataError;
+
+pub(crate) mod ctf2;
+pub(crate) mod packetized;
+pub mod tsdl;
+
+#[derive(Debug, Error)]
+pub enum MetadataError {
+    #[error("Encountered an IO error while reading the metadata. {0}")]
+    Io(#[from] io::Error),
+
+    #[error("The metadata is not valid UTF-8 text. {0}")]
+    Utf8(#[from] str::Utf8Error),
+
+    #[error(transparent)]
+    Packetized(#[from] PacketizedMetadataError),
+
+    #[error(transparent)]
+    Ctf2(#[from] Ctf2Error),
+
+    #[error("Failed to load the metadata file '{0}'. {1}")]
+    File(PathBuf, Box<MetadataError>),
+
+    #[error("The metadata directory '{0}' doesn't contain any metadata files")]
+    EmptyDirectory(PathBuf),
+
+    #[error("The metadata file '{0}' must have a trace UUID and a packet header 'uuid' field to be selected by UUID")]
+    MissingTraceUuid(PathBuf),
+
+    #[error("The metadata file '{1}' has the same trace UUID ({0}) as another metadata file")]
+    DuplicateTraceUuid(Uuid, PathBuf),
+}
+
+/// Where the metadata comes from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum MetadataSource {
+    /// A plain-text TSDL, CTF 2 or packetized metadata file, or a directory of
+    /// metadata files selected by trace UUID
+    File(PathBuf),
+    /// Metadata packets emitted by the target in the byte stream,
+    /// ahead of the data packets
+    InBand,
+}
+
+impl MetadataSource {
+    pub const IN_BAND: &'static str = "in-band";
+}
+
+/// Where the clock offset written into the metadata comes from
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ClockOffset {
+    /// The host's time when the first packet is received is taken as
+    /// the end timestamp of that packet
+    Reception,
+    /// Nanoseconds from the Unix epoch to the clock's zero, e.g. computed
+    /// from a sync event
+    Fixed(i128),
+}
+
+impl ClockOffset {
+    pub const RECEPTION: &'static str = "reception";
+}
+
+impl FromStr for ClockOffset {
+    type Err = String;
+
+    /// `reception`, or seconds with up to nanosecond precision, e.g. `1666000000.25`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case(Self::RECEPTION) {
+            return Ok(ClockOffset::Reception);
+        }
+        let err = || format!("Invalid clock offset '{}'", s);
+        let (negative, abs) = match s.strip_prefix('-') {
+            Some(abs) => (true, abs),
+            None => (false, s),
+        };
+        let (secs, frac) = abs.split_once('.').unwrap_or((abs, ""));
+        if frac.len() > 9 || !frac.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(err());
+        }
+        let secs: i128 = secs.parse().map_err(|_| err())?;
+        let nanos: i128 = format!("{:0<9}", frac).parse().map_err(|_| err())?;
+        let ns = secs * 1_000_000_000 + nanos;
+        Ok(ClockOffset::Fixed(if negative { -ns } else { ns }))
+    }
+}
+
+impl FromStr for MetadataSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().eq_ignore_ascii_case(Self::IN_BAND) {
+            Ok(MetadataSource::InBand)
+        } else {
+            Ok(MetadataSource::File(PathBuf::from(s)))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum ByteOrder {
+    LittleEndian,
+    BigEndian,
+}
+
+impl ByteOrder {
+    pub(crate) fn read_u32(self, bytes: [u8; 4]) -> u32 {
+        match self {
+            ByteOrder::LittleEndian => u32::from_le_bytes(bytes),
+            ByteOrder::BigEndian => u32::from_be_bytes(bytes),
+        }
+    }
+}
+
+/// CTF metadata, stored as plain-text TSDL
+#[derive(Debug, Clone)]
+pub struct Metadata {
+    tsdl: Bytes,
+    /// None when the TSDL is beyond what the minimal parser supports
+    schema: Option<Arc<Schema>>,
+    /// The CTF 2 metadata the TSDL was translated from
+    ctf2: Option<Bytes>,
+}
+
+impl PartialEq for Metadata {
+    fn eq(&self, other: &Self) -> bool {
+        self.tsdl == other.tsdl
+    }
+}
+
+impl Eq for Metadata {}
+
+impl Metadata {
+    /// Read a plain-text TSDL, CTF 2 or packetized metadata file
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, MetadataError> {
+        let path = path.as_ref();
+        debug!("Reading metadata file '{}'", path.display());
+        let bytes = fs::read(path)?;
+        Self::from_bytes(&bytes)
+    }
+
+    /// Plain-text TSDL, CTF 2 or packetized metadata
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, MetadataError> {
+        let text = if packetized::is_packetized(bytes) {
+            debug!("Unpacking packetized metadata");
+            packetized::unpack(bytes)?
+        } else {
+            bytes.to_vec()
+        };
+        Self::from_text(text)
+    }
+
+    /// Plain-text TSDL or CTF 2 metadata
+    pub fn from_text(text: Vec<u8>) -> Result<Self, MetadataError> {
+        if ctf2::is_ctf2(&text) {
+            debug!("Translating CTF 2 metadata to TSDL");
+            let tsdl = ctf2::to_tsdl(&text)?;
+            let mut md = Self::from_tsdl(tsdl.into_bytes())?;
+            md.ctf2 = Some(text.into());
+            Ok(md)
+        } else {
+            Self::from_tsdl(text)
+        }
+    }
+
+    /// Plain-text TSDL
+    pub fn from_tsdl(tsdl: Vec<u8>) -> Result<Self, MetadataError> {
+        let schema = match Schema::parse(str::from_utf8(&tsdl)?) {
+            Ok(s) => Some(Arc::new(s)),
+            Err(e) => {
+                debug!("Metadata schema is not available. {}", e);
+                None
+            }
+        };
+        Ok(Self {
+            tsdl: tsdl.into(),
+            schema,
+            ctf2: None,
+        })
+    }
+
+    pub fn schema(&self) -> Option<&Schema> {
+        self.schema.as_deref()
+    }
+
+    pub fn trace_uuid(&self) -> Option<Uuid> {
+        self.schema.as_ref()?.trace.uuid
+    }
+
+    /// Byte offset of the trace UUID in the packet header
+    pub fn packet_uuid_offset(&self) -> Option<usize> {
+        self.schema.as_ref()?.packet_header_uuid_offset()
+    }
+
+    /// The trace UUID in the packet header, if the header has one
+    /// and `packet` is long enough
+    pub fn packet_uuid(&self, packet: &[u8]) -> Option<Uuid> {
+        let offset = self.packet_uuid_offset()?;
+        let bytes = packet.get(offset..offset + 16)?;
+        Uuid::from_slice(bytes).ok()
+    }
+
+    /// The plain-text TSDL
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.tsdl
+    }
+
+    /// A new metadata whose clocks have their origin `offset_ns` nanoseconds
+    /// after the Unix epoch, None if the metadata clocks are unknown
+    pub fn with_clock_offset(&self, offset_ns: i128) -> Option<Self> {
+        let schema = self.schema.as_ref().filter(|s| !s.clocks.is_empty())?;
+        let tsdl = str::from_utf8(&self.tsdl).ok()?;
+
+        // (source range, replacement), applied back to front
+        let mut edits = Vec::new();
+        for clock in schema.clocks.iter() {
+            for span in clock.offset_spans.iter() {
+                edits.push((whole_lines(tsdl, span.clone()), String::new()));
+            }
+            let offset_s = offset_ns.div_euclid(1_000_000_000);
+            let cycles =
+                offset_ns.rem_euclid(1_000_000_000) * clock.freq.max(1) as i128 / 1_000_000_000;
+            edits.push((
+                clock.end..clock.end,
+                format!("\toffset_s = {};\n\toffset = {};\n", offset_s, cycles),
+            ));
+        }
+        edits.sort_by_key(|(span, _)| span.start);
+        let mut text = tsdl.to_string();
+        for (span, replacement) in edits.into_iter().rev() {
+            text.replace_range(span, &replacement);
+        }
+
+        let mut md = Self::from_tsdl(text.into_bytes()).ok()?;
+        md.ctf2 = self.ctf2.clone();
+        Some(md)
+    }
+
+    /// Whether the metadata was translated from CTF 2
+    pub fn is_ctf2(&self) -> bool {
+        self.ctf2.is_some()
+    }
+
+    /// A new metadata with additional TSDL, or CTF 2 fragments, appended,
+    /// e.g. new event classes
+    pub fn append(&self, text: &[u8]) -> Result<Self, MetadataError> {
+        let prev = self.ctf2.as_ref().unwrap_or(&self.tsdl);
+        let mut appended = Vec::with_capacity(prev.len() + text.len());
+        appended.extend_from_slice(prev);
+        appended.extend_from_slice(text);
+        Self::from_text(appended)
+    }
+
+    /// The packet decoder reads its metadata from a file, write the
+    /// plain-text TSDL to a temporary one that is removed on drop
+    #[cfg_attr(feature = "pure-rust", allow(dead_code))]
+    pub(crate) fn write_tsdl_file(&self) -> io::Result<TsdlFile> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "{}-{}-{}.tsdl",
+            env!("CARGO_PKG_NAME"),
+            process::id(),
+            COUNTER.fetch_add(1, SeqCst)
+        ));
+        fs::write(&path, &self.tsdl)?;
+        Ok(TsdlFile(path))
+    }
+}
+
+/// One or more metadata, distinguished by their trace UUID
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MetadataSet(Vec<Arc<Metadata>>);
+
+impl MetadataSet {
+    /// A metadata file, or a directory of metadata files with distinct trace UUIDs
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, MetadataError> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Ok(Arc::new(Metadata::from_file(path)?).into());
+        }
+
+        let mut set = Vec::new();
+        let mut trace_uuids = BTreeSet::new();
+        for file in metadata_dir_files(path)?.into_iter() {
+            let md = Metadata::from_file(&file)
+                .map_err(|e| MetadataError::File(file.clone(), e.into()))?;
+            let uuid = match (md.trace_uuid(), md.packet_uuid_offset()) {
+                (Some(uuid), Some(_)) => uuid,
+                _ => return Err(MetadataError::MissingTraceUuid(file)),
+            };
+            if !trace_uuids.insert(uuid) {
+                return Err(MetadataError::DuplicateTraceUuid(uuid, file));
+            }
+            info!(
+                "Using metadata file '{}' for trace UUID {}",
+                file.display(),
+                uuid
+            );
+            set.push(Arc::new(md));
+        }
+        if set.is_empty() {
+            return Err(MetadataError::EmptyDirectory(path.to_path_buf()));
+        }
+        Ok(Self(set))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Metadata>> {
+        self.0.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The metadata, when there is only one
+    pub fn single(&self) -> Option<&Arc<Metadata>> {
+        match self.0.as_slice() {
+            [md] => Some(md),
+            _ => None,
+        }
+    }
+}
+
+impl From<Arc<Metadata>> for MetadataSet {
+    fn from(md: Arc<Metadata>) -> Self {
+        Self(vec![md])
+    }
+}
+
+/// Regular, non-hidden, files sorted by name
+fn metadata_dir_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let hidden = entry.file_name().to_string_lossy().starts_with('.');
+        if !hidden && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg_attr(feature = "pure-rust", allow(dead_code))]
+pub(crate) struct TsdlFile(PathBuf);
+
+#[cfg_attr(feature = "pure-rust", allow(dead_code))]
+impl TsdlFile {
+    pub(crate) fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TsdlFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Extends a range to the whole lines when there's nothing else on them
+fn whole_lines(text: &str, span: Range<usize>) -> Range<usize> {
+    let line_start = text[..span.start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = text[span.end..]
+        .find('\n')
+        .map(|i| span.end + i + 1)
+        .unwrap_or(text.len());
+    if text[line_start..span.start].trim().is_empty() && text[span.end..line_end].trim().is_empty()
+    {
+        line_start..line_end
+    } else {
+        span
+    }
+}
+
+/// Complete TSDL metadata starts with a `/* CTF x.y` comment, complete CTF 2
+/// metadata with a preamble fragment, appended metadata doesn't
+pub(crate) fn has_preamble(text: &[u8]) -> bool {
+    if ctf2::is_ctf2(text) {
+        return ctf2::has_preamble(text);
+    }
+    text.iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map(|start| text[start..].starts_with(b"/* CTF"))
+        .unwrap_or(false)
+}
+
+/// Polls the metadata file, or directory of files, for modifications and
+/// publishes the new metadata when its content changes.
+///
+/// Returns once all the receivers are dropped.
+pub async fn watch_metadata_file(
+    path: PathBuf,
+    poll_interval: Duration,
+    updates: watch::Sender<MetadataSet>,
+) {
+    let file_state = |path: &Path| {
+        fs::metadata(path)
+            .and_then(|m| Ok((m.modified()?, m.len())))
+            .ok()
+    };
+    let files_state = |path: &Path| -> Option<Vec<(SystemTime, u64)>> {
+        if path.is_dir() {
+            metadata_dir_files(path)
+                .ok()?
+                .iter()
+                .map(|f| file_state(f))
+                .collect()
+        } else {
+            file_state(path).map(|s| vec![s])
+        }
+    };
+    let mut last_state = files_state(&path);
+    let mut interval = tokio::time::interval(poll_interval);
+    loop {
+        interval.tick().await;
+        if updates.is_closed() {
+            return;
+        }
+
+        let state = files_state(&path);
+        if state.is_none() || state == last_state {
+            continue;
+        }
+        last_state = state;
+
+        match MetadataSet::load(&path) {
+            Ok(set) => {
+                if set != *updates.borrow() {
+                    info!("Metadata '{}' was updated", path.display());
+                    let _ = updates.send(set);
+                }
+            }
+            // Could be partially written, try again on the next modification
+            Err(e) => warn!("Failed to read the updated metadata file. {}", e),
+        }
+    }
+}
@@ -0,0 +1,187 @@
+//! Packetized CTF metadata
+//!
+//! Each metadata packet starts with a `struct metadata_packet_header`
+//! (packed, in the byte order of the trace) followed by TSDL text.
+
+use crate::metadata::ByteOrder;
+use crate::packet::CtfMetadataPacketMagic;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PacketizedMetadataError {
+    #[error("Metadata packet at offset {0} is truncated")]
+    Truncated(usize),
+
+    #[error("Metadata packet at offset {0} has an invalid magic number")]
+    InvalidMagic(usize),
+
+    #[error(
+        "Metadata packet at offset {0} has an invalid size (packet_size={1}, content_size={2})"
+    )]
+    InvalidSize(usize, u32, u32),
+
+    #[error("Metadata packet at offset {0} uses a compression, encryption or checksum scheme, which is not supported")]
+    UnsupportedScheme(usize),
+}
+
+/// `struct metadata_packet_header`
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub(crate) struct MetadataPacketHeader {
+    pub byte_order: ByteOrder,
+    pub uuid: [u8; 16],
+    pub content_size_bits: u32,
+    pub packet_size_bits: u32,
+    pub compression_scheme: u8,
+    pub encryption_scheme: u8,
+    pub checksum_scheme: u8,
+}
+
+impl MetadataPacketHeader {
+    pub const WIRE_SIZE: usize = 4 + 16 + 4 + 4 + 4 + 1 + 1 + 1 + 1 + 1;
+
+    /// Returns None if there aren't enough bytes for a header or
+    /// the bytes don't start with the metadata packet magic
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let byte_order = CtfMetadataPacketMagic::check_magic(bytes)?;
+        if bytes.len() < Self::WIRE_SIZE {
+            return None;
+        }
+        let u32_at = |offset: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&bytes[offset..offset + 4]);
+            byte_order.read_u32(b)
+        };
+        let mut uuid = [0; 16];
+        uuid.copy_from_slice(&bytes[4..20]);
+        // checksum at offset 20 is unused
+        Some(Self {
+            byte_order,
+            uuid,
+            content_size_bits: u32_at(24),
+            packet_size_bits: u32_at(28),
+            compression_scheme: bytes[32],
+            encryption_scheme: bytes[33],
+            checksum_scheme: bytes[34],
+            // major and minor are ignored
+        })
+    }
+
+    pub fn packet_size(&self) -> usize {
+        self.packet_size_bits as usize >> 3
+    }
+
+    pub fn content_size(&self) -> usize {
+        self.content_size_bits as usize >> 3
+    }
+}
+
+/// A complete metadata packet
+pub(crate) struct MetadataPacket<'a> {
+    /// The TSDL text content
+    pub text: &'a [u8],
+    /// Total size of the packet in bytes, including padding
+    pub size: usize,
+}
+
+pub(crate) fn is_packetized(bytes: &[u8]) -> bool {
+    CtfMetadataPacketMagic::check_magic(bytes).is_some()
+}
+
+/// Returns the metadata packet at the start of `bytes`, or None if more bytes are needed.
+/// `offset` is only used for error reporting.
+pub(crate) fn unpack_packet(
+    bytes: &[u8],
+    offset: usize,
+) -> Result<Option<MetadataPacket<'_>>, PacketizedMetadataError> {
+    if CtfMetadataPacketMagic::check_magic(bytes).is_none() {
+        return if bytes.len() < 4 {
+            Ok(None)
+        } else {
+            Err(PacketizedMetadataError::InvalidMagic(offset))
+        };
+    }
+    let header = match MetadataPacketHeader::parse(bytes) {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    if header.compression_scheme != 0
+        || header.encryption_scheme != 0
+        || header.checksum_scheme != 0
+    {
+        return Err(PacketizedMetadataError::UnsupportedScheme(offset));
+    }
+    if header.content_size() < MetadataPacketHeader::WIRE_SIZE
+        || header.content_size() > header.packet_size()
+    {
+        return Err(PacketizedMetadataError::InvalidSize(
+            offset,
+            header.packet_size_bits,
+            header.content_size_bits,
+        ));
+    }
+    if bytes.len() < header.packet_size() {
+        return Ok(None);
+    }
+    Ok(Some(MetadataPacket {
+        text: &bytes[MetadataPacketHeader::WIRE_SIZE..header.content_size()],
+        size: header.packet_size(),
+    }))
+}
+
+/// Concatenate the TSDL text of all the metadata packets
+pub(crate) fn unpack(bytes: &[u8]) -> Result<Vec<u8>, PacketizedMetadataError> {
+    let mut tsdl = Vec::with_capacity(bytes.len());
+    let mut offset = 0;
+    while offset < bytes.len() {
+        match unpack_packet(&bytes[offset..], offset)? {
+            Some(pkt) => {
+                tsdl.extend_from_slice(pkt.text);
+                offset += pkt.size;
+            }
+            None => return Err(PacketizedMetadataError::Truncated(offset)),
+        }
+    }
+    Ok(tsdl)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn packetize(text: &[u8], byte_order: ByteOrder, padding: usize) -> Vec<u8> {
+        let to_bytes = |v: u32| match byte_order {
+            ByteOrder::LittleEndian => v.to_le_bytes(),
+            ByteOrder::BigEndian => v.to_be_bytes(),
+        };
+        let content_size = MetadataPacketHeader::WIRE_SIZE + text.len();
+        let packet_size = content_size + padding;
+        let mut pkt = Vec::new();
+        pkt.extend_from_slice(&to_bytes(CtfMetadataPacketMagic::MAGIC));
+        pkt.extend_from_slice(&[0xAB; 16]);
+        pkt.extend_from_slice(&to_bytes(0));
+        pkt.extend_from_slice(&to_bytes(content_size as u32 * 8));
+        pkt.extend_from_slice(&to_bytes(packet_size as u32 * 8));
+        pkt.extend_from_slice(&[0, 0, 0, 1, 8]);
+        pkt.extend_from_slice(text);
+        pkt.resize(packet_size, 0);
+        pkt
+    }
+
+    #[test]
+    fn unpack_packets() {
+        for byte_order in [ByteOrder::LittleEndian, ByteOrder::BigEndian] {
+            let mut bytes = packetize(b"/* CTF 1.8 */\ntrace {", byte_order, 11);
+            bytes.extend(packetize(b" major = 1; };\n", byte_order, 0));
+            assert!(is_packetized(&bytes));
+            assert_eq!(
+                unpack(&bytes).unwrap(),
+                b"/* CTF 1.8 */\ntrace { major = 1; };\n".to_vec()
+            );
+            assert!(matches!(
+                unpack(&bytes[..bytes.len() - 1]),
+                Err(PacketizedMetadataError::Truncated(_))
+            ));
+        }
+        assert!(!is_packetized(b"/* CTF 1.8 */"));
+    }
+}
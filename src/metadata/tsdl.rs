@@ -0,0 +1,1086 @@
+//! Minimal TSDL (CTF 1.8 metadata) parser
+//!
+//! Only the parts the relay needs are retained: the trace and stream
+//! attributes, the packet header and context layouts, and the clocks.
+//! Event declarations are skipped.
+
+use crate::metadata::ByteOrder;
+use std::collections::BTreeMap;
+use std::ops::Range;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Debug, Error)]
+#[error("TSDL error at line {line}. {msg}")]
+pub struct TsdlError {
+    pub line: usize,
+    pub msg: String,
+}
+
+/// The parts of the TSDL metadata the relay needs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    pub trace: TraceClass,
+    pub streams: Vec<StreamClass>,
+    pub clocks: Vec<ClockClass>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceClass {
+    pub uuid: Option<Uuid>,
+    pub byte_order: ByteOrder,
+    pub packet_header: Option<StructType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamClass {
+    pub id: Option<u64>,
+    pub packet_context: Option<StructType>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClockClass {
+    pub name: String,
+    pub freq: u64,
+    pub offset_s: i64,
+    /// In cycles
+    pub offset: u64,
+    /// Source ranges of the `offset_s` and `offset` statements
+    pub(crate) offset_spans: Vec<Range<usize>>,
+    /// Source position of the closing brace
+    pub(crate) end: usize,
+}
+
+impl ClockClass {
+    /// Default frequency when not specified
+    pub const DEFAULT_FREQ: u64 = 1_000_000_000;
+
+    /// Duration of `cycles` in nanoseconds, the clock's offset isn't included
+    pub fn cycles_to_ns(&self, cycles: u64) -> i128 {
+        cycles as i128 * 1_000_000_000 / self.freq.max(1) as i128
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldType {
+    Integer(IntegerType),
+    Struct(StructType),
+    Array(Box<FieldType>, u64),
+    /// Length is given by a previous field
+    Sequence(Box<FieldType>, String),
+    FloatingPoint {
+        size_bits: u64,
+        align_bits: u64,
+    },
+    String,
+    Variant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntegerType {
+    pub size_bits: u64,
+    pub align_bits: u64,
+    pub signed: bool,
+    /// None means the byte order of the trace
+    pub byte_order: Option<ByteOrder>,
+    /// Clock name when mapped to a clock value
+    pub clock: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StructType {
+    pub fields: Vec<(String, FieldType)>,
+    pub min_align_bits: u64,
+}
+
+impl FieldType {
+    pub fn align_bits(&self) -> u64 {
+        match self {
+            FieldType::Integer(i) => i.align_bits,
+            FieldType::Struct(s) => s.align_bits(),
+            FieldType::Array(t, _) | FieldType::Sequence(t, _) => t.align_bits(),
+            FieldType::FloatingPoint { align_bits, .. } => *align_bits,
+            FieldType::String | FieldType::Variant => 8,
+        }
+    }
+
+    /// Size of the type, if it isn't variable
+    pub fn static_size_bits(&self) -> Option<u64> {
+        match self {
+            FieldType::Integer(i) => Some(i.size_bits),
+            FieldType::Struct(s) => s.static_size_bits(),
+            FieldType::Array(t, len) => {
+                let elem = t.static_size_bits()?;
+                let stride = align_up(elem, t.align_bits());
+                Some(if *len == 0 {
+                    0
+                } else {
+                    stride * (len - 1) + elem
+                })
+            }
+            FieldType::FloatingPoint { size_bits, .. } => Some(*size_bits),
+            FieldType::Sequence(..) | FieldType::String | FieldType::Variant => None,
+        }
+    }
+
+    /// Lower bound of the size of the dynamically sized fields, alignment aside
+    pub fn min_size_bits(&self) -> u64 {
+        match self {
+            FieldType::Integer(i) => i.size_bits,
+            FieldType::Struct(s) => s.fields.iter().map(|(_, t)| t.min_size_bits()).sum(),
+            FieldType::Array(t, len) => t.min_size_bits().saturating_mul(*len),
+            FieldType::FloatingPoint { size_bits, .. } => *size_bits,
+            FieldType::String => 8,
+            FieldType::Sequence(..) | FieldType::Variant => 0,
+        }
+    }
+}
+
+impl StructType {
+    pub fn align_bits(&self) -> u64 {
+        self.fields
+            .iter()
+            .map(|(_, t)| t.align_bits())
+            .fold(self.min_align_bits.max(1), u64::max)
+    }
+
+    pub fn static_size_bits(&self) -> Option<u64> {
+        self.fields.iter().try_fold(0, |offset, (_, t)| {
+            Some(align_up(offset, t.align_bits()) + t.static_size_bits()?)
+        })
+    }
+
+    pub fn field(&self, name: &str) -> Option<&FieldType> {
+        self.fields.iter().find(|(n, _)| n == name).map(|(_, t)| t)
+    }
+
+    /// Bit offset of a top-level field, relative to the start of
+    /// the structure, if all the preceding fields have a static size
+    pub fn static_field_offset_bits(&self, name: &str) -> Option<u64> {
+        let mut offset = 0;
+        for (n, t) in self.fields.iter() {
+            offset = align_up(offset, t.align_bits());
+            if n == name {
+                return Some(offset);
+            }
+            offset += t.static_size_bits()?;
+        }
+        None
+    }
+}
+
+pub(crate) fn align_up(offset_bits: u64, align_bits: u64) -> u64 {
+    if align_bits <= 1 {
+        offset_bits
+    } else {
+        offset_bits.div_ceil(align_bits) * align_bits
+    }
+}
+
+impl Schema {
+    pub fn parse(tsdl: &str) -> Result<Self, TsdlError> {
+        Parser::new(tsdl)?.parse()
+    }
+
+    pub fn clock(&self, name: &str) -> Option<&ClockClass> {
+        self.clocks.iter().find(|c| c.name == name)
+    }
+
+    /// The clock the packet context timestamps of a stream class are mapped to,
+    /// or the only clock
+    pub fn packet_clock(&self, stream_class_id: Option<u64>) -> Option<&ClockClass> {
+        let mapped = self
+            .stream(stream_class_id)
+            .and_then(|s| s.packet_context.as_ref())
+            .and_then(|c| match c.field("timestamp_end")? {
+                FieldType::Integer(i) => i.clock.as_deref(),
+                _ => None,
+            });
+        match mapped {
+            Some(name) => self.clock(name),
+            None if self.clocks.len() == 1 => self.clocks.first(),
+            None => None,
+        }
+    }
+
+    /// Width of the packet context timestamps of a stream class
+    pub fn packet_timestamp_bits(&self, stream_class_id: Option<u64>) -> Option<u64> {
+        let ctx = self.stream(stream_class_id)?.packet_context.as_ref()?;
+        match ctx
+            .field("timestamp_begin")
+            .or(ctx.field("timestamp_end"))?
+        {
+            FieldType::Integer(i) => Some(i.size_bits),
+            _ => None,
+        }
+    }
+
+    /// The stream class with the given ID, or the only one
+    /// when the packet header has no `stream_id`
+    pub fn stream(&self, id: Option<u64>) -> Option<&StreamClass> {
+        match id {
+            Some(id) => self.streams.iter().find(|s| s.id.unwrap_or(0) == id),
+            None if self.streams.len() == 1 => self.streams.first(),
+            None => None,
+        }
+    }
+
+    /// Byte offset of the 16-byte `uuid` field in the packet header
+    pub fn packet_header_uuid_offset(&self) -> Option<usize> {
+        let hdr = self.trace.packet_header.as_ref()?;
+        match hdr.field("uuid")? {
+            FieldType::Array(elem, 16) => match elem.as_ref() {
+                FieldType::Integer(i) if i.size_bits == 8 => (),
+                _ => return None,
+            },
+            _ => return None,
+        }
+        let offset_bits = hdr.static_field_offset_bits("uuid")?;
+        if offset_bits % 8 == 0 {
+            Some((offset_bits / 8) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Tok {
+    Ident(String),
+    Int(u64),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    tok: Tok,
+    start: usize,
+}
+
+const PUNCTS: &[&str] = &[
+    ":=", "->", "...", "{", "}", "[", "]", "(", ")", ";", ":", "=", ",", ".", "<", ">", "+", "-",
+    "*",
+];
+
+fn tokenize(src: &str) -> Result<Vec<Token>, TsdlError> {
+    let bytes = src.as_bytes();
+    let err = |at: usize, msg: &str| TsdlError {
+        line: line_at(src, at),
+        msg: msg.to_string(),
+    };
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if src[i..].starts_with("/*") {
+            i = src[i + 2..]
+                .find("*/")
+                .map(|end| i + 2 + end + 2)
+                .ok_or_else(|| err(start, "Unterminated comment"))?;
+        } else if src[i..].starts_with("//") {
+            i = src[i..]
+                .find('\n')
+                .map(|end| i + end)
+                .unwrap_or(bytes.len());
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token {
+                tok: Tok::Ident(src[start..i].to_string()),
+                start,
+            });
+        } else if c.is_ascii_digit() {
+            while i < bytes.len() && bytes[i].is_ascii_alphanumeric() {
+                i += 1;
+            }
+            let lit = src[start..i].trim_end_matches(['u', 'U', 'l', 'L']);
+            let value = if let Some(hex) = lit.strip_prefix("0x").or(lit.strip_prefix("0X")) {
+                u64::from_str_radix(hex, 16)
+            } else if lit.len() > 1 && lit.starts_with('0') {
+                u64::from_str_radix(&lit[1..], 8)
+            } else {
+                lit.parse::<u64>()
+            }
+            .map_err(|_| err(start, "Invalid integer literal"))?;
+            tokens.push(Token {
+                tok: Tok::Int(value),
+                start,
+            });
+        } else if c == b'"' || c == b'\'' {
+            i += 1;
+            let mut s = String::new();
+            loop {
+                match bytes.get(i) {
+                    None => return Err(err(start, "Unterminated literal")),
+                    Some(b'\\') => {
+                        if let Some(escaped) = src[i + 1..].chars().next() {
+                            s.push(escaped);
+                            i += 1 + escaped.len_utf8();
+                        } else {
+                            i += 1;
+                        }
+                    }
+                    Some(q) if *q == c => {
+                        i += 1;
+                        break;
+                    }
+                    Some(_) => {
+                        let ch = src[i..].chars().next().unwrap_or_default();
+                        s.push(ch);
+                        i += ch.len_utf8();
+                    }
+                }
+            }
+            tokens.push(Token {
+                tok: Tok::Str(s),
+                start,
+            });
+        } else if let Some(p) = PUNCTS.iter().find(|p| src[i..].starts_with(**p)) {
+            i += p.len();
+            tokens.push(Token {
+                tok: Tok::Punct(p),
+                start,
+            });
+        } else {
+            return Err(err(start, &format!("Unexpected character '{}'", c as char)));
+        }
+    }
+    Ok(tokens)
+}
+
+fn line_at(src: &str, offset: usize) -> usize {
+    src.as_bytes()[..offset.min(src.len())]
+        .iter()
+        .filter(|b| **b == b'\n')
+        .count()
+        + 1
+}
+
+/// Attribute values
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+    Int(i128),
+    Str(String),
+    /// Identifiers and dotted paths, e.g. `le` or `clock.monotonic.value`
+    Path(String),
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    type_aliases: BTreeMap<String, FieldType>,
+    named_structs: BTreeMap<String, StructType>,
+    named_enums: BTreeMap<String, FieldType>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(src: &'a str) -> Result<Self, TsdlError> {
+        Ok(Self {
+            src,
+            tokens: tokenize(src)?,
+            pos: 0,
+            type_aliases: Default::default(),
+            named_structs: Default::default(),
+            named_enums: Default::default(),
+        })
+    }
+
+    fn err<T>(&self, msg: impl Into<String>) -> Result<T, TsdlError> {
+        Err(TsdlError {
+            line: line_at(self.src, self.source_pos()),
+            msg: msg.into(),
+        })
+    }
+
+    fn peek(&self) -> Option<&Tok> {
+        self.tokens.get(self.pos).map(|t| &t.tok)
+    }
+
+    fn peek_at(&self, n: usize) -> Option<&Tok> {
+        self.tokens.get(self.pos + n).map(|t| &t.tok)
+    }
+
+    fn next(&mut self) -> Option<Tok> {
+        let t = self.tokens.get(self.pos).map(|t| t.tok.clone());
+        self.pos += 1;
+        t
+    }
+
+    fn is_punct(&self, p: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Punct(x)) if *x == p)
+    }
+
+    fn is_ident(&self, id: &str) -> bool {
+        matches!(self.peek(), Some(Tok::Ident(x)) if x == id)
+    }
+
+    fn eat_punct(&mut self, p: &str) -> bool {
+        if self.is_punct(p) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect_punct(&mut self, p: &str) -> Result<(), TsdlError> {
+        if self.eat_punct(p) {
+            Ok(())
+        } else {
+            self.err(format!("Expected '{}'", p))
+        }
+    }
+
+    fn expect_ident(&mut self) -> Result<String, TsdlError> {
+        match self.peek() {
+            Some(Tok::Ident(id)) => {
+                let id = id.clone();
+                self.pos += 1;
+                Ok(id)
+            }
+            _ => self.err("Expected an identifier"),
+        }
+    }
+
+    fn parse(mut self) -> Result<Schema, TsdlError> {
+        let mut trace = None;
+        let mut streams = Vec::new();
+        let mut clocks = Vec::new();
+        while let Some(tok) = self.peek().cloned() {
+            match tok {
+                Tok::Ident(id) if id == "trace" => {
+                    self.pos += 1;
+                    trace = Some(self.parse_trace()?);
+                }
+                Tok::Ident(id) if id == "stream" => {
+                    self.pos += 1;
+                    streams.push(self.parse_stream()?);
+                }
+                Tok::Ident(id) if id == "clock" => {
+                    self.pos += 1;
+                    clocks.push(self.parse_clock()?);
+                }
+                Tok::Ident(id) if matches!(id.as_str(), "env" | "event" | "callsite") => {
+                    self.pos += 1;
+                    self.skip_block()?;
+                }
+                Tok::Ident(id) if id == "typealias" => self.parse_typealias()?,
+                Tok::Ident(id) if id == "typedef" => self.parse_typedef()?,
+                Tok::Punct(";") => self.pos += 1,
+                _ => {
+                    // Named struct/enum/variant declarations
+                    self.parse_type(false)?;
+                    self.expect_punct(";")?;
+                }
+            }
+        }
+
+        let trace = match trace {
+            Some(t) => t,
+            None => return self.err("Missing trace block"),
+        };
+        Ok(Schema {
+            trace,
+            streams,
+            clocks,
+        })
+    }
+
+    fn skip_block(&mut self) -> Result<(), TsdlError> {
+        self.expect_punct("{")?;
+        let mut depth = 1;
+        while depth != 0 {
+            match self.next() {
+                Some(Tok::Punct("{")) => depth += 1,
+                Some(Tok::Punct("}")) => depth -= 1,
+                Some(_) => (),
+                None => return self.err("Unterminated block"),
+            }
+        }
+        self.expect_punct(";")
+    }
+
+    /// Dotted attribute names, e.g. `packet.header`
+    fn parse_path(&mut self) -> Result<String, TsdlError> {
+        let mut path = self.expect_ident()?;
+        while self.eat_punct(".") {
+            path.push('.');
+            path.push_str(&self.expect_ident()?);
+        }
+        Ok(path)
+    }
+
+    fn parse_value(&mut self) -> Result<Value, TsdlError> {
+        let negative = self.eat_punct("-");
+        match self.peek().cloned() {
+            Some(Tok::Int(v)) => {
+                self.pos += 1;
+                Ok(Value::Int(if negative { -(v as i128) } else { v as i128 }))
+            }
+            Some(Tok::Str(s)) if !negative => {
+                self.pos += 1;
+                Ok(Value::Str(s))
+            }
+            Some(Tok::Ident(_)) if !negative => Ok(Value::Path(self.parse_path()?)),
+            _ => self.err("Expected a value"),
+        }
+    }
+
+    /// Parses the statements of a block, attributes are returned,
+    /// type assignments (`:=`) are given to `on_type`
+    fn parse_block_body<F>(&mut self, mut on_type: F) -> Result<Vec<(String, Value)>, TsdlError>
+    where
+        F: FnMut(&str, FieldType),
+    {
+        let mut attrs = Vec::new();
+        self.expect_punct("{")?;
+        while !self.eat_punct("}") {
+            if self.is_ident("typealias") {
+                self.parse_typealias()?;
+                continue;
+            } else if self.is_ident("typedef") {
+                self.parse_typedef()?;
+                continue;
+            } else if self.eat_punct(";") {
+                continue;
+            }
+            let name = self.parse_path()?;
+            if self.eat_punct(":=") {
+                let t = self.parse_type(false)?;
+                on_type(&name, t);
+            } else {
+                self.expect_punct("=")?;
+                attrs.push((name, self.parse_value()?));
+            }
+            self.expect_punct(";")?;
+        }
+        self.expect_punct(";")?;
+        Ok(attrs)
+    }
+
+    fn parse_trace(&mut self) -> Result<TraceClass, TsdlError> {
+        let mut packet_header = None;
+        let attrs = self.parse_block_body(|name, t| {
+            if name == "packet.header" {
+                if let FieldType::Struct(s) = t {
+                    packet_header = Some(s);
+                }
+            }
+        })?;
+
+        let mut uuid = None;
+        let mut byte_order = None;
+        for (name, value) in attrs.into_iter() {
+            match (name.as_str(), value) {
+                ("uuid", Value::Str(s)) => match Uuid::parse_str(&s) {
+                    Ok(u) => uuid = Some(u),
+                    Err(_) => return self.err(format!("Invalid trace UUID '{}'", s)),
+                },
+                ("byte_order", Value::Path(bo)) => byte_order = parse_byte_order(&bo),
+                _ => (),
+            }
+        }
+        let byte_order = match byte_order {
+            Some(bo) => bo,
+            None => return self.err("The trace block is missing a valid 'byte_order'"),
+        };
+        Ok(TraceClass {
+            uuid,
+            byte_order,
+            packet_header,
+        })
+    }
+
+    fn parse_stream(&mut self) -> Result<StreamClass, TsdlError> {
+        let mut packet_context = None;
+        let attrs = self.parse_block_body(|name, t| {
+            if name == "packet.context" {
+                if let FieldType::Struct(s) = t {
+                    packet_context = Some(s);
+                }
+            }
+        })?;
+        let id = attrs.into_iter().find_map(|(name, value)| match value {
+            Value::Int(id) if name == "id" => Some(id as u64),
+            _ => None,
+        });
+        Ok(StreamClass { id, packet_context })
+    }
+
+    /// Keeps track of where the offsets are to rewrite them
+    fn parse_clock(&mut self) -> Result<ClockClass, TsdlError> {
+        let mut clock = ClockClass {
+            name: String::new(),
+            freq: ClockClass::DEFAULT_FREQ,
+            offset_s: 0,
+            offset: 0,
+            offset_spans: Vec::new(),
+            end: 0,
+        };
+        self.expect_punct("{")?;
+        while !self.is_punct("}") {
+            if self.eat_punct(";") {
+                continue;
+            }
+            let start = self.source_pos();
+            let name = self.parse_path()?;
+            self.expect_punct("=")?;
+            let value = self.parse_value()?;
+            let end = self.source_pos() + 1;
+            self.expect_punct(";")?;
+            match (name.as_str(), value) {
+                ("name", Value::Path(n) | Value::Str(n)) => clock.name = n,
+                ("freq", Value::Int(f)) => clock.freq = f as u64,
+                ("offset_s", Value::Int(o)) => {
+                    clock.offset_s = o as i64;
+                    clock.offset_spans.push(start..end);
+                }
+                ("offset", Value::Int(o)) => {
+                    clock.offset = o as u64;
+                    clock.offset_spans.push(start..end);
+                }
+                _ => (),
+            }
+        }
+        clock.end = self.source_pos();
+        self.expect_punct("}")?;
+        self.expect_punct(";")?;
+        if clock.name.is_empty() {
+            return self.err("The clock block is missing a 'name'");
+        }
+        Ok(clock)
+    }
+
+    fn source_pos(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map(|t| t.start)
+            .unwrap_or(self.src.len())
+    }
+
+    fn parse_typealias(&mut self) -> Result<(), TsdlError> {
+        self.expect_ident()?; // typealias
+        let t = self.parse_type(false)?;
+        self.expect_punct(":=")?;
+        let mut names = Vec::new();
+        while !self.is_punct(";") {
+            names.push(self.expect_ident()?);
+        }
+        self.expect_punct(";")?;
+        self.type_aliases.insert(names.join(" "), t);
+        Ok(())
+    }
+
+    fn parse_typedef(&mut self) -> Result<(), TsdlError> {
+        self.expect_ident()?; // typedef
+        let t = self.parse_type(true)?;
+        let (name, t) = self.parse_declarator(t)?;
+        self.expect_punct(";")?;
+        self.type_aliases.insert(name, t);
+        Ok(())
+    }
+
+    /// Field name and optional array/sequence lengths
+    fn parse_declarator(&mut self, mut t: FieldType) -> Result<(String, FieldType), TsdlError> {
+        let name = self.expect_ident()?;
+        let mut lengths = Vec::new();
+        while self.eat_punct("[") {
+            lengths.push(match self.peek().cloned() {
+                Some(Tok::Int(len)) => {
+                    self.pos += 1;
+                    Ok(len)
+                }
+                Some(Tok::Ident(_)) => Err(self.parse_path()?),
+                _ => return self.err("Expected an array length"),
+            });
+            self.expect_punct("]")?;
+        }
+        // Innermost dimension is the last one
+        for len in lengths.into_iter().rev() {
+            t = match len {
+                Ok(len) => FieldType::Array(Box::new(t), len),
+                Err(field) => FieldType::Sequence(Box::new(t), field),
+            };
+        }
+        Ok((name, t))
+    }
+
+    /// `declarator_follows` leaves the last identifier of a type name
+    /// for the declarator, e.g. `unsigned long len`
+    fn parse_type(&mut self, declarator_follows: bool) -> Result<FieldType, TsdlError> {
+        let keyword = match self.peek() {
+            Some(Tok::Ident(id)) => id.clone(),
+            _ => return self.err("Expected a type"),
+        };
+        match keyword.as_str() {
+            "integer" => {
+                self.pos += 1;
+                let attrs = self.parse_attr_block()?;
+                Ok(FieldType::Integer(self.integer_from_attrs(&attrs)?))
+            }
+            "floating_point" => {
+                self.pos += 1;
+                let attrs = self.parse_attr_block()?;
+                let get = |n: &str| attr_int(&attrs, n).unwrap_or(0) as u64;
+                let size_bits = get("exp_dig") + get("mant_dig");
+                let align_bits = attr_int(&attrs, "align").map(|a| a as u64).unwrap_or(8);
+                Ok(FieldType::FloatingPoint {
+                    size_bits,
+                    align_bits,
+                })
+            }
+            "string" => {
+                self.pos += 1;
+                if self.is_punct("{") {
+                    self.parse_attr_block()?;
+                }
+                Ok(FieldType::String)
+            }
+            "struct" => {
+                self.pos += 1;
+                self.parse_struct().map(FieldType::Struct)
+            }
+            "variant" => {
+                self.pos += 1;
+                self.parse_variant()
+            }
+            "enum" => {
+                self.pos += 1;
+                self.parse_enum()
+            }
+            _ => {
+                let mut names = Vec::new();
+                while let Some(Tok::Ident(id)) = self.peek() {
+                    names.push(id.clone());
+                    self.pos += 1;
+                }
+                if declarator_follows && names.len() > 1 {
+                    names.pop();
+                    self.pos -= 1;
+                }
+                let name = names.join(" ");
+                match self.type_aliases.get(&name) {
+                    Some(t) => Ok(t.clone()),
+                    None => self.err(format!("Unknown type '{}'", name)),
+                }
+            }
+        }
+    }
+
+    fn parse_attr_block(&mut self) -> Result<Vec<(String, Value)>, TsdlError> {
+        let mut attrs = Vec::new();
+        self.expect_punct("{")?;
+        while !self.eat_punct("}") {
+            if self.eat_punct(";") {
+                continue;
+            }
+            let name = self.parse_path()?;
+            self.expect_punct("=")?;
+            attrs.push((name, self.parse_value()?));
+            if !self.is_punct("}") {
+                self.expect_punct(";")?;
+            }
+        }
+        Ok(attrs)
+    }
+
+    fn integer_from_attrs(&self, attrs: &[(String, Value)]) -> Result<IntegerType, TsdlError> {
+        let size_bits = match attr_int(attrs, "size") {
+            Some(s) if s > 0 && s <= 64 => s as u64,
+            _ => return self.err("Integer requires a 'size' between 1 and 64"),
+        };
+        let align_bits = attr_int(attrs, "align")
+            .map(|a| a as u64)
+            .unwrap_or(if size_bits % 8 == 0 { 8 } else { 1 });
+        let signed = attrs.iter().any(|(n, v)| {
+            n == "signed"
+                && match v {
+                    Value::Int(i) => *i != 0,
+                    Value::Path(p) => p.eq_ignore_ascii_case("true"),
+                    _ => false,
+                }
+        });
+        let byte_order = attrs.iter().find_map(|(n, v)| match v {
+            // 'native' is the byte order of the trace
+            Value::Path(p) if n == "byte_order" => parse_byte_order(p),
+            _ => None,
+        });
+        let clock = attrs.iter().find_map(|(n, v)| match v {
+            Value::Path(p) if n == "map" => p
+                .strip_prefix("clock.")
+                .and_then(|c| c.strip_suffix(".value"))
+                .map(str::to_string),
+            _ => None,
+        });
+        Ok(IntegerType {
+            size_bits,
+            align_bits,
+            signed,
+            byte_order,
+            clock,
+        })
+    }
+
+    fn parse_struct(&mut self) -> Result<StructType, TsdlError> {
+        let name = match self.peek() {
+            Some(Tok::Ident(_)) => Some(self.expect_ident()?),
+            _ => None,
+        };
+        if !self.is_punct("{") {
+            return match name.as_ref().and_then(|n| self.named_structs.get(n)) {
+                Some(s) => Ok(s.clone()),
+                None => self.err(format!("Unknown struct '{}'", name.unwrap_or_default())),
+            };
+        }
+        self.expect_punct("{")?;
+        let mut s = StructType::default();
+        while !self.eat_punct("}") {
+            if self.is_ident("typealias") {
+                self.parse_typealias()?;
+                continue;
+            } else if self.is_ident("typedef") {
+                self.parse_typedef()?;
+                continue;
+            } else if self.eat_punct(";") {
+                continue;
+            }
+            let t = self.parse_type(true)?;
+            loop {
+                let (name, t) = self.parse_declarator(t.clone())?;
+                s.fields.push((name, t));
+                if !self.eat_punct(",") {
+                    break;
+                }
+            }
+            self.expect_punct(";")?;
+        }
+        if self.is_ident("align") {
+            self.pos += 1;
+            self.expect_punct("(")?;
+            s.min_align_bits = match self.next() {
+                Some(Tok::Int(a)) => a,
+                _ => return self.err("Expected an alignment value"),
+            };
+            self.expect_punct(")")?;
+        }
+        if let Some(n) = name {
+            self.named_structs.insert(n, s.clone());
+        }
+        Ok(s)
+    }
+
+    fn parse_variant(&mut self) -> Result<FieldType, TsdlError> {
+        if let Some(Tok::Ident(_)) = self.peek() {
+            self.pos += 1;
+        }
+        if self.eat_punct("<") {
+            self.parse_path()?;
+            self.expect_punct(">")?;
+        }
+        if self.is_punct("{") {
+            let mut depth = 0;
+            loop {
+                match self.next() {
+                    Some(Tok::Punct("{")) => depth += 1,
+                    Some(Tok::Punct("}")) => {
+                        depth -= 1;
+                        if depth == 0 {
+                            break;
+                        }
+                    }
+                    Some(_) => (),
+                    None => return self.err("Unterminated variant"),
+                }
+            }
+        }
+        Ok(FieldType::Variant)
+    }
+
+    fn parse_enum(&mut self) -> Result<FieldType, TsdlError> {
+        let name = match (self.peek(), self.peek_at(1)) {
+            (Some(Tok::Ident(_)), Some(Tok::Punct(":" | "{"))) => Some(self.expect_ident()?),
+            (Some(Tok::Ident(_)), None) | (Some(Tok::Ident(_)), Some(Tok::Punct(";"))) => {
+                Some(self.expect_ident()?)
+            }
+            _ => None,
+        };
+        if !self.eat_punct(":") {
+            if !self.is_punct("{") {
+                return match name.as_ref().and_then(|n| self.named_enums.get(n)) {
+                    Some(t) => Ok(t.clone()),
+                    None => self.err(format!("Unknown enum '{}'", name.unwrap_or_default())),
+                };
+            }
+            // Defaults to 'int'
+            let int = self.type_aliases.get("int").cloned();
+            return match int {
+                Some(t) => {
+                    self.skip_enum_body()?;
+                    Ok(t)
+                }
+                None => self.err("Enum without a container type requires an 'int' type alias"),
+            };
+        }
+        let container = self.parse_type(false)?;
+        self.skip_enum_body()?;
+        if let Some(n) = name {
+            self.named_enums.insert(n, container.clone());
+        }
+        Ok(container)
+    }
+
+    fn skip_enum_body(&mut self) -> Result<(), TsdlError> {
+        self.expect_punct("{")?;
+        loop {
+            match self.next() {
+                Some(Tok::Punct("}")) => return Ok(()),
+                Some(_) => (),
+                None => return self.err("Unterminated enum"),
+            }
+        }
+    }
+}
+
+fn attr_int(attrs: &[(String, Value)], name: &str) -> Option<i128> {
+    attrs.iter().find_map(|(n, v)| match v {
+        Value::Int(i) if n == name => Some(*i),
+        _ => None,
+    })
+}
+
+fn parse_byte_order(s: &str) -> Option<ByteOrder> {
+    match s {
+        "le" => Some(ByteOrder::LittleEndian),
+        "be" | "network" => Some(ByteOrder::BigEndian),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const TSDL: &str = r#"/* CTF 1.8 */
+
+typealias integer { size = 8; align = 8; signed = false; } := uint8_t;
+typealias integer { size = 16; align = 8; signed = false; } := uint16_t;
+typealias integer { size = 32; align = 8; signed = false; } := uint32_t;
+typealias integer { size = 64; align = 8; signed = false; } := uint64_t;
+typealias integer { size = 5; align = 1; signed = false; } := uint5_t;
+typealias integer { size = 27; align = 1; signed = false; } := uint27_t;
+
+trace {
+    major = 1;
+    minor = 8;
+    uuid = "79e49040-21b5-42d4-a873-677261696e65";
+    byte_order = le;
+    packet.header := struct {
+        uint32_t magic;
+        uint8_t  uuid[16];
+        uint32_t stream_id;
+        uint64_t stream_instance_id;
+    };
+};
+
+env {
+    domain = "ust";
+    tracer_name = "lttng-ust";
+};
+
+clock {
+    name = "monotonic";
+    uuid = "00000000-0000-0000-0000-000000000000";
+    description = "Monotonic Clock";
+    freq = 1000000000; /* Frequency, in Hz */
+    /* clock value offset from Epoch is: offset * (1/freq) */
+    offset = 1648069229180718640;
+};
+
+typealias integer {
+    size = 32; align = 8; signed = false;
+    map = clock.monotonic.value;
+} := uint32_clock_monotonic_t;
+
+typealias integer {
+    size = 64; align = 8; signed = false;
+    map = clock.monotonic.value;
+} := uint64_clock_monotonic_t;
+
+struct packet_context {
+    uint64_clock_monotonic_t timestamp_begin;
+    uint64_clock_monotonic_t timestamp_end;
+    uint64_t content_size;
+    uint64_t packet_size;
+    uint64_t packet_seq_num;
+    unsigned long events_discarded;
+    uint32_t cpu_id;
+};
+
+struct event_header_compact {
+    enum : uint5_t { compact = 0 ... 30, extended = 31 } id;
+    variant <id> {
+        struct {
+            uint27_clock_monotonic_t timestamp;
+        } compact;
+        struct {
+            uint32_t id;
+            uint64_clock_monotonic_t timestamp;
+        } extended;
+    } v;
+} align(8);
+
+stream {
+    id = 0;
+    event.header := struct event_header_compact;
+    packet.context := struct packet_context;
+};
+
+event {
+    name = "init";
+    id = 0;
+    stream_id = 0;
+    fields := struct {
+        integer { size = 8; align = 8; signed = 1; encoding = none; base = 10; } _id;
+        string _name;
+    };
+};
+"#;
+
+    pub(crate) fn tsdl() -> String {
+        // 'unsigned long' and 'uint27_clock_monotonic_t' exercise multi-word names
+        // and late aliases
+        TSDL.replace(
+            "typealias integer { size = 27; align = 1; signed = false; } := uint27_t;",
+            "typealias integer { size = 27; align = 1; signed = false; } := uint27_t;\n\
+             typealias integer { size = 27; align = 1; signed = false; map = clock.monotonic.value; } := uint27_clock_monotonic_t;\n\
+             typealias integer { size = 64; align = 8; signed = false; } := unsigned long;",
+        )
+    }
+
+    #[test]
+    fn parse_schema() {
+        let schema = Schema::parse(&tsdl()).unwrap();
+        assert_eq!(
+            schema.trace.uuid,
+            Some(Uuid::parse_str("79e49040-21b5-42d4-a873-677261696e65").unwrap())
+        );
+        assert_eq!(schema.trace.byte_order, ByteOrder::LittleEndian);
+        assert_eq!(schema.packet_header_uuid_offset(), Some(4));
+        assert_eq!(schema.streams.len(), 1);
+        assert_eq!(schema.streams[0].id, Some(0));
+        let clock = schema.packet_clock(Some(0)).unwrap();
+        assert_eq!(clock.name, "monotonic");
+        assert_eq!(clock.freq, 1_000_000_000);
+        assert_eq!(clock.offset, 1648069229180718640);
+
+        let err =
+            Schema::parse("trace {\n byte_order = le;\n packet.header := foo;\n};").unwrap_err();
+        assert_eq!(err.line, 3);
+    }
+}
@@ -1,78 +1,520 @@
-use crate::packet::{CtfPacket, CtfPacketMagic};
+use crate::metadata::packetized::{self, PacketizedMetadataError};
+use crate::metadata::{self, ClockOffset, Metadata, MetadataError, MetadataSet};
+use crate::packet::decoder::{PacketDecoder, PacketDecoderConfig, PacketProperties};
+use crate::packet::framing::{Deframer, Framing};
+use crate::packet::timestamp::TimestampExtender;
+use crate::packet::{CtfMetadataPacketMagic, CtfPacket, CtfPacketMagic};
 use crate::relayd::wire::Index;
-use babeltrace2_sys::internal_api::{PacketDecoder, PacketDecoderConfig, PacketProperties};
-use babeltrace2_sys::Error;
-use bytes::{Bytes, BytesMut};
-use std::io;
+use bytes::{Buf, Bytes, BytesMut};
 use std::num::NonZeroU64;
-use std::path::Path;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{fmt, io, mem};
 use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, watch};
 use tokio_util::codec::{Decoder, Encoder};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
 #[derive(Debug, Error)]
 pub enum DecoderError {
+    #[cfg(feature = "babeltrace")]
     #[error("{0}")]
-    Babeltrace(#[from] Error),
+    Babeltrace(#[from] babeltrace2_sys::Error),
+
+    #[cfg(feature = "pure-rust")]
+    #[error("{0}")]
+    PacketDecode(#[from] crate::packet::decoder::PacketDecodeError),
 
     #[error("Encountered in IO error while reading. {0}")]
     Io(#[from] io::Error),
+
+    #[error("Encountered an invalid in-band metadata packet. {0}")]
+    InBandMetadataPacket(#[from] PacketizedMetadataError),
+
+    #[error("Encountered invalid in-band metadata. {0}")]
+    InBandMetadata(#[from] MetadataError),
 }
 
 pub struct CtfPacketCodec {
-    dec: PacketDecoder,
+    config: PacketDecoderConfig,
+    /// Looking for metadata packets in the byte stream
+    in_band_metadata: bool,
+    /// In-band metadata received so far, not yet given to a decoder
+    pending_metadata: Vec<u8>,
+    /// Externally provided metadata updates, e.g. a modified metadata file
+    metadata_updates: Option<watch::Receiver<MetadataSet>>,
+    /// One per metadata, empty until the metadata is known
+    decoders: Vec<ActiveDecoder>,
+    dropped_without_metadata: bool,
+    /// Packets dropped because their trace UUID doesn't match the metadata
+    uuid_mismatches: u64,
+    clock_offset: Option<ClockOffset>,
+    /// Set once the clock offset is known, nanoseconds from the Unix epoch
+    clock_offset_ns: Option<i128>,
+    timestamps: TimestampExtender,
+    /// Set when the packets are wrapped in serial line frames
+    deframer: Option<Deframer>,
+    /// Payloads of the frames, not yet decoded into packets
+    deframed: BytesMut,
+    /// Receives the bytes found between packets, e.g. a text console
+    console: Option<mpsc::Sender<Bytes>>,
 }
 
-// PacketDecoder has raw pointers, but it's all reentrant
-unsafe impl Send for CtfPacketCodec {}
+struct ActiveDecoder {
+    metadata: Arc<Metadata>,
+    /// The metadata given to the packets, with the clock offset applied
+    published: Arc<Metadata>,
+    dec: PacketDecoder,
+}
 
-impl CtfPacketCodec {
-    pub fn new<P: AsRef<Path>>(
-        metadata_path: P,
+impl ActiveDecoder {
+    fn new(
+        metadata: Arc<Metadata>,
         config: &PacketDecoderConfig,
+        clock_offset_ns: Option<i128>,
     ) -> Result<Self, DecoderError> {
-        let dec = PacketDecoder::new(metadata_path, config)?;
-        Ok(Self { dec })
+        let dec = PacketDecoder::new(&metadata, config)?;
+        let mut d = Self {
+            published: metadata.clone(),
+            metadata,
+            dec,
+        };
+        if let Some(ns) = clock_offset_ns {
+            d.apply_clock_offset(ns);
+        }
+        Ok(d)
+    }
+
+    fn apply_clock_offset(&mut self, offset_ns: i128) {
+        match self.metadata.with_clock_offset(offset_ns) {
+            Some(md) => self.published = Arc::new(md),
+            None => warn!("The metadata clocks are unknown, the clock offset can't be applied"),
+        }
     }
 }
 
-impl Decoder for CtfPacketCodec {
-    type Item = CtfPacket;
-    type Error = DecoderError;
+impl CtfPacketCodec {
+    /// When there are multiple metadata, each packet is decoded with the
+    /// metadata whose trace UUID matches the one in the packet header
+    pub fn new(metadata: &MetadataSet, config: &PacketDecoderConfig) -> Result<Self, DecoderError> {
+        let decoders = metadata
+            .iter()
+            .map(|md| ActiveDecoder::new(md.clone(), config, None))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self {
+            config: *config,
+            in_band_metadata: false,
+            pending_metadata: Vec::new(),
+            metadata_updates: None,
+            decoders,
+            dropped_without_metadata: false,
+            uuid_mismatches: 0,
+            clock_offset: None,
+            clock_offset_ns: None,
+            timestamps: TimestampExtender::default(),
+            deframer: None,
+            deframed: BytesMut::new(),
+            console: None,
+        })
+    }
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // Find start of packet if we can
-        let mut found_magic = false;
-        for idx in 0..src.len() {
-            if CtfPacketMagic::check_magic(&src[idx..]) {
+    /// The decoders are rebuilt with the new metadata whenever it changes.
+    /// Packets already buffered are decoded with the new metadata.
+    pub fn with_metadata_updates(mut self, updates: watch::Receiver<MetadataSet>) -> Self {
+        self.metadata_updates = Some(updates);
+        self
+    }
+
+    /// Write a clock offset into the metadata given to the packets,
+    /// so traces from different targets line up.
+    /// The packet timestamps are left untouched.
+    pub fn with_clock_offset(mut self, clock_offset: ClockOffset) -> Self {
+        self.clock_offset = Some(clock_offset);
+        if let ClockOffset::Fixed(ns) = clock_offset {
+            self.set_clock_offset(ns);
+        }
+        self
+    }
+
+    /// Unwrap the packets from serial line frames before looking for them.
+    /// Malformed frames, and HDLC frames with a bad CRC, are dropped.
+    pub fn with_framing(mut self, framing: Framing) -> Self {
+        self.deframer = match framing {
+            Framing::None => None,
+            f => Some(Deframer::new(f)),
+        };
+        self
+    }
+
+    /// Send the bytes found between packets on the channel instead of
+    /// discarding them. They're dropped when the channel is full.
+    pub fn with_console(mut self, console: mpsc::Sender<Bytes>) -> Self {
+        self.console = Some(console);
+        self
+    }
+
+    fn forward_console(&mut self, bytes: BytesMut) {
+        let console = match self.console.as_ref() {
+            Some(c) => c,
+            None => return,
+        };
+        match console.try_send(bytes.freeze()) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => debug!("Dropping console bytes, the channel is full"),
+            Err(TrySendError::Closed(_)) => self.console = None,
+        }
+    }
+
+    fn set_clock_offset(&mut self, offset_ns: i128) {
+        info!(
+            "Using a clock offset of {} ns from the Unix epoch",
+            offset_ns
+        );
+        self.clock_offset_ns = Some(offset_ns);
+        for d in self.decoders.iter_mut() {
+            d.apply_clock_offset(offset_ns);
+        }
+    }
+
+    /// The packet was received now, its end timestamp gives the offset
+    fn set_reception_clock_offset(&mut self, metadata: &Metadata, index: &Index) {
+        let now_ns = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(d) => d.as_nanos() as i128,
+            Err(_) => return,
+        };
+        let clock = metadata
+            .schema()
+            .and_then(|s| s.packet_clock(Some(index.stream_id)));
+        match clock {
+            Some(c) => self.set_clock_offset(now_ns - c.cycles_to_ns(index.timestamp_end)),
+            None => {
+                warn!("The packet clock is unknown, the clock offset can't be computed");
+                // Don't try again
+                self.clock_offset = None;
+            }
+        }
+    }
+
+    /// The metadata is received in the byte stream as metadata packets.
+    /// It is considered complete once the first data packet is found.
+    ///
+    /// Metadata packets following data packets are either appended to
+    /// the current metadata, or replace it if they start a new TSDL document.
+    pub fn with_in_band_metadata(config: &PacketDecoderConfig) -> Self {
+        Self {
+            config: *config,
+            in_band_metadata: true,
+            pending_metadata: Vec::new(),
+            metadata_updates: None,
+            decoders: Vec::new(),
+            dropped_without_metadata: false,
+            uuid_mismatches: 0,
+            clock_offset: None,
+            clock_offset_ns: None,
+            timestamps: TimestampExtender::default(),
+            deframer: None,
+            deframed: BytesMut::new(),
+            console: None,
+        }
+    }
+
+    /// Returns false if more bytes are needed.
+    /// An invalid metadata packet is skipped.
+    fn decode_metadata_packet(&mut self, src: &mut BytesMut) -> bool {
+        let pkt_size = match packetized::unpack_packet(src, 0) {
+            Ok(Some(pkt)) => {
+                self.pending_metadata.extend_from_slice(pkt.text);
+                pkt.size
+            }
+            Ok(None) => return false,
+            Err(e) => {
+                warn!("{}", DecoderError::from(e));
+                // Skip the magic so we can resync on the next packet
+                src.advance(4);
+                return true;
+            }
+        };
+        debug!("Consumed in-band metadata packet, len={pkt_size}");
+        src.advance(pkt_size);
+        true
+    }
+
+    fn complete_in_band_metadata(&mut self) -> Result<(), DecoderError> {
+        let text = mem::take(&mut self.pending_metadata);
+        let metadata = match self.decoders.first() {
+            Some(active) if !metadata::has_preamble(&text) => {
+                info!("Received {} bytes of appended in-band metadata", text.len());
+                active.metadata.append(&text)?
+            }
+            _ => {
+                info!("Received {} bytes of in-band metadata", text.len());
+                Metadata::from_text(text)?
+            }
+        };
+        self.update_metadata(Arc::new(metadata).into())
+    }
+
+    fn check_metadata_updates(&mut self) {
+        let metadata = match self.metadata_updates.as_mut() {
+            Some(updates) if updates.has_changed().unwrap_or(false) => {
+                updates.borrow_and_update().clone()
+            }
+            _ => return,
+        };
+        if let Err(e) = self.update_metadata(metadata) {
+            warn!(
+                "Keeping the previous metadata, the update is unusable. {}",
+                e
+            );
+        }
+    }
+
+    /// Replace the decoders, the previous ones are kept if any of the new metadata is unusable.
+    /// Decoders of unchanged metadata are reused.
+    fn update_metadata(&mut self, metadata: MetadataSet) -> Result<(), DecoderError> {
+        let mut new_decoders = Vec::new();
+        for md in metadata.iter() {
+            if !self.decoders.iter().any(|d| d.metadata == *md) {
+                debug!("Building a packet decoder for the updated metadata");
+                new_decoders.push(ActiveDecoder::new(
+                    md.clone(),
+                    &self.config,
+                    self.clock_offset_ns,
+                )?);
+            }
+        }
+        self.decoders
+            .retain(|d| metadata.iter().any(|md| *md == d.metadata));
+        self.decoders.extend(new_decoders);
+        Ok(())
+    }
+
+    /// Number of packets dropped so far because their trace UUID
+    /// didn't match the metadata
+    pub fn uuid_mismatches(&self) -> u64 {
+        self.uuid_mismatches
+    }
+
+    /// Number of frames dropped so far because they were malformed
+    /// or their CRC didn't match
+    pub fn bad_frames(&self) -> u64 {
+        self.deframer.as_ref().map_or(0, |d| d.bad_frames())
+    }
+
+    /// Logs the number of packets and frames dropped so far, if any
+    pub fn report_drops(&self, source: &dyn fmt::Display) {
+        if self.uuid_mismatches != 0 {
+            warn!(
+                "Dropped {} packets from {} because their trace UUID didn't match the metadata",
+                self.uuid_mismatches, source
+            );
+        }
+        let bad_frames = self.bad_frames();
+        if bad_frames != 0 {
+            warn!(
+                "Dropped {} frames from {} because they were malformed or their CRC didn't match",
+                bad_frames, source
+            );
+        }
+    }
+
+    /// Find the decoder whose trace UUID matches the packet at the start of `src`.
+    /// Metadata without a trace UUID, or without a packet header 'uuid' field,
+    /// can't be verified and accepts any packet.
+    fn select_decoder(&self, src: &[u8]) -> Selection {
+        let mut packet_uuid = None;
+        for (idx, d) in self.decoders.iter().enumerate() {
+            let (trace_uuid, offset) =
+                match (d.metadata.trace_uuid(), d.metadata.packet_uuid_offset()) {
+                    (Some(uuid), Some(offset)) => (uuid, offset),
+                    _ if self.decoders.len() == 1 => return Selection::Decoder(idx),
+                    _ => continue,
+                };
+            if src.len() < offset + 16 {
+                return Selection::NeedMoreBytes;
+            }
+            let uuid = d.metadata.packet_uuid(src);
+            if uuid == Some(trace_uuid) {
+                return Selection::Decoder(idx);
+            }
+            packet_uuid = packet_uuid.or(uuid);
+        }
+        Selection::Mismatch(packet_uuid.unwrap_or_else(Uuid::nil))
+    }
+
+    fn decode_packet(&mut self, src: &mut BytesMut) -> Result<Option<CtfPacket>, DecoderError> {
+        self.check_metadata_updates();
+
+        loop {
+            // Find start of packet if we can
+            let mut found_magic = None;
+            for idx in 0..src.len() {
+                let magic = if CtfPacketMagic::check_magic(&src[idx..]) {
+                    Magic::Packet
+                } else if self.in_band_metadata
+                    && CtfMetadataPacketMagic::check_magic(&src[idx..]).is_some()
+                {
+                    Magic::Metadata
+                } else {
+                    continue;
+                };
                 debug!("Found magic at offset {idx}, len={}", src.len());
                 if idx != 0 {
-                    let mut junk = src.split_to(idx);
-                    junk.clear();
+                    let junk = src.split_to(idx);
+                    self.forward_console(junk);
                 }
-                found_magic = true;
+                found_magic = Some(magic);
                 break;
             }
-        }
 
-        if !found_magic {
-            return Ok(None);
-        }
+            match found_magic {
+                None => {
+                    // Keep what could be the start of a magic
+                    let keep = CtfPacketMagic::MAGIC.len() - 1;
+                    if self.console.is_some() && src.len() > keep {
+                        let junk = src.split_to(src.len() - keep);
+                        self.forward_console(junk);
+                    }
+                    return Ok(None);
+                }
+                Some(Magic::Metadata) => {
+                    if !self.decode_metadata_packet(src) {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                Some(Magic::Packet) => (),
+            }
 
-        match self.dec.packet_properties(src) {
-            Err(_) => {
-                // Assume this is because not enough bytes to parse full packet header
-                // since we've got a magic already
-                Ok(None)
+            // A data packet marks the end of the in-band metadata packets
+            if !self.pending_metadata.is_empty() {
+                if let Err(e) = self.complete_in_band_metadata() {
+                    warn!("Ignoring the in-band metadata received. {}", e);
+                }
+            }
+
+            if self.decoders.is_empty() {
+                // Can't know the size of the packet without metadata,
+                // skip the magic and resync on the next one
+                if !self.dropped_without_metadata {
+                    warn!("Dropping packet data received before the in-band metadata");
+                    self.dropped_without_metadata = true;
+                }
+                src.advance(CtfPacketMagic::MAGIC.len());
+                continue;
             }
-            Ok(None) => Ok(None),
-            Ok(Some(p)) => Ok(props_to_packet(&p, src)),
+
+            let idx = match self.select_decoder(src) {
+                Selection::Decoder(idx) => idx,
+                Selection::NeedMoreBytes => return Ok(None),
+                Selection::Mismatch(packet_uuid) => {
+                    // The packet size can't be trusted without the right metadata,
+                    // skip the magic and resync on the next packet
+                    self.drop_uuid_mismatch(packet_uuid);
+                    src.advance(CtfPacketMagic::MAGIC.len());
+                    continue;
+                }
+            };
+
+            let active = &mut self.decoders[idx];
+            let mut pkt = match active.dec.packet_properties(src) {
+                Ok(None) => return Ok(None),
+                Ok(Some(p)) => props_to_packet(&p, src, &active.published),
+                Err(e) => {
+                    warn!("Dropping a packet that can't be decoded. {}", e);
+                    // Skip the magic and resync on the next packet
+                    src.advance(CtfPacketMagic::MAGIC.len());
+                    continue;
+                }
+            };
+
+            if let Some(pkt) = pkt.as_mut() {
+                // Narrow clocks wrap, the index needs monotonic timestamps
+                let width_bits = self.decoders[idx]
+                    .metadata
+                    .schema()
+                    .and_then(|s| s.packet_timestamp_bits(Some(pkt.index.stream_id)));
+                if let Some(width_bits) = width_bits {
+                    self.timestamps.extend(width_bits, &mut pkt.index);
+                }
+
+                if self.clock_offset == Some(ClockOffset::Reception)
+                    && self.clock_offset_ns.is_none()
+                {
+                    let metadata = self.decoders[idx].metadata.clone();
+                    self.set_reception_clock_offset(&metadata, &pkt.index);
+                    pkt.metadata = self.decoders[idx].published.clone();
+                }
+            }
+            return Ok(pkt);
+        }
+    }
+
+    fn drop_uuid_mismatch(&mut self, packet_uuid: Uuid) {
+        self.uuid_mismatches += 1;
+        if self.uuid_mismatches == 1 {
+            let expected: Vec<String> = self
+                .decoders
+                .iter()
+                .filter_map(|d| d.metadata.trace_uuid())
+                .map(|u| u.to_string())
+                .collect();
+            warn!(
+                "Dropping packets with trace UUID {}, the metadata expects {}. Was the target flashed with a different build?",
+                packet_uuid,
+                expected.join(", ")
+            );
+        } else {
+            debug!(
+                "Dropped packet with trace UUID {}, {} mismatching packets so far",
+                packet_uuid, self.uuid_mismatches
+            );
         }
     }
 }
 
-fn props_to_packet(p: &PacketProperties, src: &mut BytesMut) -> Option<CtfPacket> {
-    props_to_index(p, src).map(|(index, packet)| CtfPacket { index, packet })
+enum Magic {
+    Packet,
+    Metadata,
+}
+
+enum Selection {
+    Decoder(usize),
+    NeedMoreBytes,
+    /// The packet's trace UUID doesn't match any of the metadata
+    Mismatch(Uuid),
+}
+
+impl Decoder for CtfPacketCodec {
+    type Item = CtfPacket;
+    type Error = DecoderError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let mut deframer = match self.deframer.take() {
+            Some(d) => d,
+            None => return self.decode_packet(src),
+        };
+        let mut deframed = mem::take(&mut self.deframed);
+        deframer.deframe(src, &mut deframed);
+        let res = self.decode_packet(&mut deframed);
+        self.deframer = Some(deframer);
+        self.deframed = deframed;
+        res
+    }
+}
+
+fn props_to_packet(
+    p: &PacketProperties,
+    src: &mut BytesMut,
+    metadata: &Arc<Metadata>,
+) -> Option<CtfPacket> {
+    props_to_index(p, src).map(|(index, packet)| CtfPacket {
+        index,
+        packet,
+        metadata: metadata.clone(),
+    })
 }
 
 fn props_to_index(p: &PacketProperties, src: &mut BytesMut) -> Option<(Index, Bytes)> {
@@ -0,0 +1,42 @@
+use super::PacketProperties;
+use crate::metadata::Metadata;
+use crate::packet::DecoderError;
+use babeltrace2_sys::internal_api;
+
+pub use internal_api::PacketDecoderConfig;
+
+pub struct PacketDecoder(internal_api::PacketDecoder);
+
+// PacketDecoder has raw pointers, but it's all reentrant
+unsafe impl Send for PacketDecoder {}
+
+impl PacketDecoder {
+    pub fn new(metadata: &Metadata, config: &PacketDecoderConfig) -> Result<Self, DecoderError> {
+        // The decoder reads its metadata from a file
+        let tsdl_file = metadata.write_tsdl_file()?;
+        let dec = internal_api::PacketDecoder::new(tsdl_file.path(), config)?;
+        Ok(Self(dec))
+    }
+
+    /// Returns None if more bytes are needed
+    pub fn packet_properties(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<Option<PacketProperties>, DecoderError> {
+        match self.0.packet_properties(packet) {
+            // Assume this is because not enough bytes to parse full packet header
+            // since we've got a magic already
+            Err(_) => Ok(None),
+            Ok(p) => Ok(p.map(|p| PacketProperties {
+                packet_total_size_bits: p.packet_total_size_bits,
+                packet_content_size_bits: p.packet_content_size_bits,
+                stream_class_id: p.stream_class_id,
+                data_stream_id: p.data_stream_id,
+                discarded_events: p.discarded_events,
+                packet_seq_num: p.packet_seq_num,
+                beginning_clock: p.beginning_clock,
+                end_clock: p.end_clock,
+            })),
+        }
+    }
+}
@@ -0,0 +1,31 @@
+//! Packet header and context decoding
+//!
+//! libbabeltrace2 decodes the packets with the `babeltrace` feature (default),
+//! a pure-Rust decoder with the `pure-rust` feature. The pure-Rust decoder
+//! takes precedence when both are enabled.
+
+#[cfg(not(any(feature = "babeltrace", feature = "pure-rust")))]
+compile_error!("Either the 'babeltrace' or the 'pure-rust' feature is required");
+
+#[cfg(all(feature = "babeltrace", not(feature = "pure-rust")))]
+pub use self::babeltrace::{PacketDecoder, PacketDecoderConfig};
+#[cfg(feature = "pure-rust")]
+pub use native::{PacketDecodeError, PacketDecoder, PacketDecoderConfig};
+
+#[cfg(all(feature = "babeltrace", not(feature = "pure-rust")))]
+mod babeltrace;
+#[cfg(feature = "pure-rust")]
+mod native;
+
+/// The packet header and context fields the relay needs
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub struct PacketProperties {
+    pub packet_total_size_bits: Option<u64>,
+    pub packet_content_size_bits: Option<u64>,
+    pub stream_class_id: Option<u64>,
+    pub data_stream_id: Option<u64>,
+    pub discarded_events: Option<u64>,
+    pub packet_seq_num: Option<u64>,
+    pub beginning_clock: Option<u64>,
+    pub end_clock: Option<u64>,
+}
@@ -0,0 +1,301 @@
+//! Pure-Rust packet header and context decoder
+//!
+//! The layouts come from the minimal TSDL parser, fields are
+//! recognized by name the same way libbabeltrace2 does.
+
+use super::PacketProperties;
+use crate::metadata::tsdl::{align_up, FieldType, Schema, StructType, TsdlError};
+use crate::metadata::{ByteOrder, Metadata};
+use crate::packet::DecoderError;
+use std::collections::BTreeMap;
+use std::str;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum PacketDecodeError {
+    #[error("The metadata can't be used by the pure-Rust decoder. {0}")]
+    Metadata(#[from] TsdlError),
+
+    #[error("The packet header refers to stream class {0:?}, which isn't in the metadata")]
+    UnknownStreamClass(Option<u64>),
+
+    #[error("The packet {0} has a variant field, which the pure-Rust decoder doesn't support")]
+    Variant(&'static str),
+
+    #[error("The packet {0} sequence length field '{1}' wasn't decoded before the sequence")]
+    SequenceLength(&'static str, String),
+
+    #[error("The packet {0} sequence '{1}' of {2} elements doesn't fit in the packet")]
+    SequenceTooLong(&'static str, String, u64),
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Default)]
+pub struct PacketDecoderConfig;
+
+pub struct PacketDecoder {
+    schema: Schema,
+}
+
+impl PacketDecoder {
+    pub fn new(metadata: &Metadata, _config: &PacketDecoderConfig) -> Result<Self, DecoderError> {
+        // Parse again rather than use the metadata's schema to report why it's unusable
+        let tsdl = str::from_utf8(metadata.as_bytes())
+            .map_err(|e| DecoderError::InBandMetadata(e.into()))?;
+        let schema = Schema::parse(tsdl).map_err(PacketDecodeError::from)?;
+        Ok(Self { schema })
+    }
+
+    /// Returns None if more bytes are needed
+    pub fn packet_properties(
+        &mut self,
+        packet: &[u8],
+    ) -> Result<Option<PacketProperties>, DecoderError> {
+        match self.decode(packet) {
+            Ok(p) => Ok(Some(p)),
+            Err(Stop::NeedMoreBytes) => Ok(None),
+            Err(Stop::Error(e)) => Err(e.into()),
+        }
+    }
+
+    fn decode(&self, packet: &[u8]) -> Result<PacketProperties, Stop> {
+        let mut r = Reader {
+            bytes: packet,
+            offset_bits: 0,
+            byte_order: self.schema.trace.byte_order,
+            lengths: BTreeMap::new(),
+        };
+
+        let header = match self.schema.trace.packet_header.as_ref() {
+            Some(h) => r.scope(h, "header")?,
+            None => BTreeMap::new(),
+        };
+        let stream_class_id = header.get("stream_id").copied();
+        let stream = self
+            .schema
+            .stream(stream_class_id)
+            .ok_or(PacketDecodeError::UnknownStreamClass(stream_class_id))?;
+        let context = match stream.packet_context.as_ref() {
+            Some(c) => r.scope(c, "context")?,
+            None => BTreeMap::new(),
+        };
+
+        Ok(PacketProperties {
+            packet_total_size_bits: context.get("packet_size").copied(),
+            packet_content_size_bits: context.get("content_size").copied(),
+            stream_class_id: stream_class_id.or(stream.id),
+            data_stream_id: header.get("stream_instance_id").copied(),
+            discarded_events: context.get("events_discarded").copied(),
+            packet_seq_num: context.get("packet_seq_num").copied(),
+            beginning_clock: context.get("timestamp_begin").copied(),
+            end_clock: context.get("timestamp_end").copied(),
+        })
+    }
+}
+
+enum Stop {
+    NeedMoreBytes,
+    Error(PacketDecodeError),
+}
+
+impl From<PacketDecodeError> for Stop {
+    fn from(e: PacketDecodeError) -> Self {
+        Stop::Error(e)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset_bits: u64,
+    /// The trace's, for integers without one
+    byte_order: ByteOrder,
+    /// Integer values by field name, for the sequence lengths
+    lengths: BTreeMap<String, u64>,
+}
+
+impl<'a> Reader<'a> {
+    /// Top-level integer fields of a scope by name
+    fn scope(
+        &mut self,
+        s: &StructType,
+        scope: &'static str,
+    ) -> Result<BTreeMap<String, u64>, Stop> {
+        let mut values = BTreeMap::new();
+        self.offset_bits = align_up(self.offset_bits, s.align_bits());
+        for (name, t) in s.fields.iter() {
+            if let Some(v) = self.field(name, t, scope)? {
+                values.insert(name.clone(), v);
+            }
+        }
+        Ok(values)
+    }
+
+    /// Returns the value of integer fields
+    fn field(
+        &mut self,
+        name: &str,
+        t: &FieldType,
+        scope: &'static str,
+    ) -> Result<Option<u64>, Stop> {
+        self.offset_bits = align_up(self.offset_bits, t.align_bits());
+        match t {
+            FieldType::Integer(i) => {
+                let v = self.read_uint(i.size_bits, i.byte_order.unwrap_or(self.byte_order))?;
+                self.lengths.insert(name.to_string(), v);
+                return Ok(Some(v));
+            }
+            FieldType::Struct(s) => {
+                for (name, t) in s.fields.iter() {
+                    self.field(name, t, scope)?;
+                }
+            }
+            FieldType::Array(elem, len) => match t.static_size_bits() {
+                Some(size) => self.skip(size)?,
+                None => {
+                    for _ in 0..*len {
+                        self.field(name, elem, scope)?;
+                    }
+                }
+            },
+            FieldType::Sequence(elem, len_field) => {
+                let len_name = len_field.rsplit('.').next().unwrap_or(len_field);
+                let len = match self.lengths.get(len_name) {
+                    Some(len) => *len,
+                    None => {
+                        return Err(
+                            PacketDecodeError::SequenceLength(scope, len_field.clone()).into()
+                        )
+                    }
+                };
+                // The length comes from the packet, a corrupt one mustn't keep
+                // decoding elements that take no bits, or more than the packet has
+                let elem_bits = elem.min_size_bits();
+                let remaining_bits = self
+                    .lengths
+                    .get("packet_size")
+                    .map_or(u64::MAX, |size| size.saturating_sub(self.offset_bits));
+                if len != 0 && (elem_bits == 0 || len > remaining_bits / elem_bits) {
+                    return Err(
+                        PacketDecodeError::SequenceTooLong(scope, name.to_string(), len).into(),
+                    );
+                }
+                for _ in 0..len {
+                    self.field(name, elem, scope)?;
+                }
+            }
+            FieldType::FloatingPoint { size_bits, .. } => self.skip(*size_bits)?,
+            FieldType::String => {
+                let start = (self.offset_bits / 8) as usize;
+                let nul = self
+                    .bytes
+                    .get(start..)
+                    .and_then(|b| b.iter().position(|b| *b == 0))
+                    .ok_or(Stop::NeedMoreBytes)?;
+                self.offset_bits += (nul as u64 + 1) * 8;
+            }
+            FieldType::Variant => return Err(PacketDecodeError::Variant(scope).into()),
+        }
+        Ok(None)
+    }
+
+    fn skip(&mut self, size_bits: u64) -> Result<(), Stop> {
+        if self.offset_bits + size_bits > self.bytes.len() as u64 * 8 {
+            return Err(Stop::NeedMoreBytes);
+        }
+        self.offset_bits += size_bits;
+        Ok(())
+    }
+
+    /// CTF bit order follows the byte order: least significant bit
+    /// first for little-endian, most significant bit first for big-endian
+    fn read_uint(&mut self, size_bits: u64, byte_order: ByteOrder) -> Result<u64, Stop> {
+        let start = self.offset_bits;
+        self.skip(size_bits)?;
+        let bit = |k: u64, shift: u64| ((self.bytes[(k / 8) as usize] >> shift) & 1) as u64;
+        let v = if start.is_multiple_of(8) && size_bits.is_multiple_of(8) {
+            let bytes = &self.bytes[(start / 8) as usize..((start + size_bits) / 8) as usize];
+            match byte_order {
+                ByteOrder::LittleEndian => bytes.iter().rev().fold(0, |v, b| v << 8 | *b as u64),
+                ByteOrder::BigEndian => bytes.iter().fold(0, |v, b| v << 8 | *b as u64),
+            }
+        } else {
+            (0..size_bits).fold(0, |v, i| {
+                let k = start + i;
+                match byte_order {
+                    ByteOrder::LittleEndian => v | bit(k, k % 8) << i,
+                    ByteOrder::BigEndian => v << 1 | bit(k, 7 - k % 8),
+                }
+            })
+        };
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::tsdl::tests::tsdl;
+
+    #[test]
+    fn decode_packet_properties() {
+        let md = Metadata::from_tsdl(tsdl().into_bytes()).unwrap();
+        let mut dec = PacketDecoder::new(&md, &Default::default()).unwrap();
+
+        let mut pkt = Vec::new();
+        pkt.extend_from_slice(&0xC1FC_1FC1_u32.to_le_bytes());
+        pkt.extend_from_slice(&[0xAB; 16]);
+        pkt.extend_from_slice(&0_u32.to_le_bytes());
+        pkt.extend_from_slice(&7_u64.to_le_bytes());
+        for v in [100_u64, 200, 1024, 2048, 3, 4] {
+            pkt.extend_from_slice(&v.to_le_bytes());
+        }
+        pkt.extend_from_slice(&1_u32.to_le_bytes());
+
+        assert_eq!(dec.packet_properties(&pkt[..pkt.len() - 1]).unwrap(), None);
+        assert_eq!(
+            dec.packet_properties(&pkt).unwrap(),
+            Some(PacketProperties {
+                packet_total_size_bits: Some(2048),
+                packet_content_size_bits: Some(1024),
+                stream_class_id: Some(0),
+                data_stream_id: Some(7),
+                discarded_events: Some(4),
+                packet_seq_num: Some(3),
+                beginning_clock: Some(100),
+                end_clock: Some(200),
+            })
+        );
+    }
+
+    #[test]
+    fn reject_oversized_sequences() {
+        let tsdl = tsdl().replace(
+            "uint32_t cpu_id;",
+            "uint32_t cpu_id; uint64_t len; uint8_t bytes[len]; struct { } empty[len];",
+        );
+        let md = Metadata::from_tsdl(tsdl.into_bytes()).unwrap();
+        let mut dec = PacketDecoder::new(&md, &Default::default()).unwrap();
+
+        let packet = |len: u64| {
+            let mut pkt = Vec::new();
+            pkt.extend_from_slice(&0xC1FC_1FC1_u32.to_le_bytes());
+            pkt.extend_from_slice(&[0xAB; 16]);
+            pkt.extend_from_slice(&0_u32.to_le_bytes());
+            pkt.extend_from_slice(&7_u64.to_le_bytes());
+            for v in [100_u64, 200, 1024, 2048, 3, 4] {
+                pkt.extend_from_slice(&v.to_le_bytes());
+            }
+            pkt.extend_from_slice(&1_u32.to_le_bytes());
+            pkt.extend_from_slice(&len.to_le_bytes());
+            pkt.extend_from_slice(&[0; 4]);
+            pkt
+        };
+
+        // More bytes than the 2048 bits packet has
+        let err = dec.packet_properties(&packet(u64::MAX)).unwrap_err();
+        assert!(err.to_string().contains("'bytes'"), "{}", err);
+
+        // The bytes fit, the empty structures would be decoded endlessly
+        let err = dec.packet_properties(&packet(4)).unwrap_err();
+        assert!(err.to_string().contains("'empty'"), "{}", err);
+    }
+}
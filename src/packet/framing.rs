@@ -0,0 +1,245 @@
+//! De-framing of CTF packets wrapped in serial line frames
+//!
+//! The payloads of the good frames are concatenated and given to the
+//! magic-scanning packet decoder, a CTF packet can span several frames.
+
+use bytes::{Buf, BytesMut};
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug, Default)]
+pub enum Framing {
+    /// The CTF packets are sent as-is
+    #[default]
+    None,
+    /// Consistent Overhead Byte Stuffing, frames end with a zero byte
+    Cobs,
+    /// RFC 1055 Serial Line IP framing
+    Slip,
+    /// RFC 1662 HDLC-like framing, 0x7E flags and a trailing little-endian
+    /// CRC-16/X.25 frame check sequence, without address and control fields
+    Hdlc,
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_lowercase().as_str() {
+            "none" => Framing::None,
+            "cobs" => Framing::Cobs,
+            "slip" => Framing::Slip,
+            "hdlc" => Framing::Hdlc,
+            _ => return Err("Invalid framing, use none, cobs, slip or hdlc".to_string()),
+        })
+    }
+}
+
+/// Frames larger than this are dropped, the delimiter was probably lost
+const MAX_FRAME_SIZE: usize = 1024 * 1024;
+
+const SLIP_END: u8 = 0xC0;
+const SLIP_ESC: u8 = 0xDB;
+const SLIP_ESC_END: u8 = 0xDC;
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+const HDLC_FLAG: u8 = 0x7E;
+const HDLC_ESC: u8 = 0x7D;
+const HDLC_ESC_XOR: u8 = 0x20;
+/// The FCS over a frame and its own FCS
+const HDLC_GOOD_FCS: u16 = 0xF0B8;
+
+pub(crate) struct Deframer {
+    framing: Framing,
+    /// Raw bytes of the frame being received
+    frame: Vec<u8>,
+    /// Frames dropped because they're malformed or their CRC doesn't match
+    bad_frames: u64,
+}
+
+impl Deframer {
+    pub fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            frame: Vec::new(),
+            bad_frames: 0,
+        }
+    }
+
+    pub fn bad_frames(&self) -> u64 {
+        self.bad_frames
+    }
+
+    /// Consumes all of `src`, the payloads of complete frames are appended to `dst`
+    pub fn deframe(&mut self, src: &mut BytesMut, dst: &mut BytesMut) {
+        let delimiter = match self.framing {
+            Framing::None => {
+                dst.extend_from_slice(src);
+                src.clear();
+                return;
+            }
+            Framing::Cobs => 0,
+            Framing::Slip => SLIP_END,
+            Framing::Hdlc => HDLC_FLAG,
+        };
+        while !src.is_empty() {
+            match src.iter().position(|b| *b == delimiter) {
+                Some(end) => {
+                    self.frame.extend_from_slice(&src[..end]);
+                    src.advance(end + 1);
+                    // Back-to-back delimiters are allowed between frames
+                    if !self.frame.is_empty() {
+                        let frame = std::mem::take(&mut self.frame);
+                        match self.decode_frame(&frame) {
+                            Some(payload) => dst.extend_from_slice(&payload),
+                            None => self.drop_bad_frame(frame.len()),
+                        }
+                    }
+                }
+                None => {
+                    self.frame.extend_from_slice(src);
+                    src.clear();
+                    if self.frame.len() > MAX_FRAME_SIZE {
+                        let len = self.frame.len();
+                        self.frame.clear();
+                        self.drop_bad_frame(len);
+                    }
+                }
+            }
+        }
+    }
+
+    fn decode_frame(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        match self.framing {
+            Framing::None => Some(frame.to_vec()),
+            Framing::Cobs => cobs_decode(frame),
+            Framing::Slip => unescape(frame, SLIP_ESC, |b| match b {
+                SLIP_ESC_END => Some(SLIP_END),
+                SLIP_ESC_ESC => Some(SLIP_ESC),
+                _ => None,
+            }),
+            Framing::Hdlc => {
+                let mut payload = unescape(frame, HDLC_ESC, |b| Some(b ^ HDLC_ESC_XOR))?;
+                if payload.len() < 2 || crc16_x25(&payload) != HDLC_GOOD_FCS {
+                    return None;
+                }
+                payload.truncate(payload.len() - 2);
+                Some(payload)
+            }
+        }
+    }
+
+    fn drop_bad_frame(&mut self, len: usize) {
+        self.bad_frames += 1;
+        if self.bad_frames == 1 {
+            warn!("Dropping a bad {:?} frame of {} bytes", self.framing, len);
+        } else {
+            debug!(
+                "Dropped a bad {:?} frame of {} bytes, {} bad frames so far",
+                self.framing, len, self.bad_frames
+            );
+        }
+    }
+}
+
+fn cobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut idx = 0;
+    while idx < frame.len() {
+        let code = frame[idx] as usize;
+        let block = frame.get(idx + 1..idx + code)?;
+        out.extend_from_slice(block);
+        idx += code;
+        // A maximal block isn't followed by an implicit zero, nor is the last one
+        if code < 0xFF && idx < frame.len() {
+            out.push(0);
+        }
+    }
+    Some(out)
+}
+
+fn unescape(frame: &[u8], esc: u8, unescaped: impl Fn(u8) -> Option<u8>) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(frame.len());
+    let mut bytes = frame.iter();
+    while let Some(b) = bytes.next() {
+        if *b == esc {
+            out.push(unescaped(*bytes.next()?)?);
+        } else {
+            out.push(*b);
+        }
+    }
+    Some(out)
+}
+
+/// RFC 1662 FCS-16
+fn crc16_x25(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0xFFFF, |crc, b| {
+        (0..8).fold(crc ^ *b as u16, |crc, _| {
+            if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deframe(framing: Framing, chunks: &[&[u8]]) -> (Vec<u8>, u64) {
+        let mut d = Deframer::new(framing);
+        let mut dst = BytesMut::new();
+        for c in chunks {
+            d.deframe(&mut BytesMut::from(*c), &mut dst);
+        }
+        (dst.to_vec(), d.bad_frames())
+    }
+
+    #[test]
+    fn deframe_frames() {
+        assert_eq!(
+            deframe(
+                Framing::Cobs,
+                &[&[0x03, 0x11, 0x22, 0x02], &[0x33, 0x00, 0x00]]
+            ),
+            (vec![0x11, 0x22, 0x00, 0x33], 0)
+        );
+        assert_eq!(
+            deframe(Framing::Cobs, &[&[0x05, 0x11, 0x00, 0x02, 0x01, 0x00]]),
+            (vec![0x01], 1)
+        );
+
+        assert_eq!(
+            deframe(
+                Framing::Slip,
+                &[&[SLIP_END, 0x11, SLIP_ESC, SLIP_ESC_END, 0x22, SLIP_END]]
+            ),
+            (vec![0x11, SLIP_END, 0x22], 0)
+        );
+
+        let mut payload = vec![0x11, HDLC_FLAG, 0x22];
+        let fcs = !crc16_x25(&payload);
+        payload.extend_from_slice(&fcs.to_le_bytes());
+        let mut frame = vec![HDLC_FLAG];
+        for b in payload {
+            if b == HDLC_FLAG || b == HDLC_ESC {
+                frame.extend_from_slice(&[HDLC_ESC, b ^ HDLC_ESC_XOR]);
+            } else {
+                frame.push(b);
+            }
+        }
+        frame.push(HDLC_FLAG);
+        let (good, _) = frame.split_at(frame.len() / 2);
+        assert_eq!(
+            deframe(Framing::Hdlc, &[good, &frame[good.len()..]]),
+            (vec![0x11, HDLC_FLAG, 0x22], 0)
+        );
+        let corrupted: Vec<u8> = frame
+            .iter()
+            .map(|b| if *b == 0x22 { 0x23 } else { *b })
+            .collect();
+        assert_eq!(deframe(Framing::Hdlc, &[&corrupted]), (vec![], 1));
+    }
+}
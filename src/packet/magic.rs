@@ -1,3 +1,5 @@
+use crate::metadata::ByteOrder;
+
 pub struct CtfPacketMagic;
 
 impl CtfPacketMagic {
@@ -7,3 +9,23 @@ impl CtfPacketMagic {
         (input.len() >= Self::MAGIC.len()) && (&input[..4] == Self::MAGIC)
     }
 }
+
+pub struct CtfMetadataPacketMagic;
+
+impl CtfMetadataPacketMagic {
+    pub const MAGIC: u32 = 0x75D1_1D57;
+
+    /// Metadata packet headers use the byte order of the trace,
+    /// returns the byte order of the magic if found
+    pub(crate) fn check_magic(input: &[u8]) -> Option<ByteOrder> {
+        if input.len() < 4 {
+            None
+        } else if input[..4] == Self::MAGIC.to_le_bytes() {
+            Some(ByteOrder::LittleEndian)
+        } else if input[..4] == Self::MAGIC.to_be_bytes() {
+            Some(ByteOrder::BigEndian)
+        } else {
+            None
+        }
+    }
+}
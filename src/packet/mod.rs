@@ -1,16 +1,26 @@
+use crate::metadata::Metadata;
 use crate::relayd::wire::Index;
 use bytes::Bytes;
 use std::fmt;
+use std::sync::Arc;
 
 pub use codec::{CtfPacketCodec, DecoderError};
-pub use magic::CtfPacketMagic;
+pub use decoder::{PacketDecoderConfig, PacketProperties};
+pub use framing::Framing;
+pub use magic::{CtfMetadataPacketMagic, CtfPacketMagic};
 
 pub(crate) mod codec;
+pub(crate) mod decoder;
+pub(crate) mod framing;
 pub(crate) mod magic;
+pub(crate) mod timestamp;
 
+#[derive(Clone)]
 pub struct CtfPacket {
     pub index: Index,
     pub packet: Bytes,
+    /// The metadata the packet was decoded with
+    pub metadata: Arc<Metadata>,
 }
 
 impl fmt::Display for CtfPacket {
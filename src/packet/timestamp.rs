@@ -0,0 +1,91 @@
+//! Timestamp wrap-around reconstruction
+//!
+//! Targets with narrow cycle counters (e.g. 32 bits) wrap every few seconds.
+//! relayd's index and the live viewers expect monotonic timestamps, so the
+//! packet timestamps are extended to 64 bits, like the CTF decoders do
+//! for the event timestamps.
+
+use crate::relayd::wire::{Index, OptionalIndexField};
+use std::collections::BTreeMap;
+
+/// Last extended timestamp of each stream, by stream class and instance ID
+#[derive(Debug, Default)]
+pub(crate) struct TimestampExtender {
+    last: BTreeMap<(u64, OptionalIndexField), u64>,
+}
+
+impl TimestampExtender {
+    /// Extends the index timestamps of `width_bits` wide clock values
+    pub(crate) fn extend(&mut self, width_bits: u64, index: &mut Index) {
+        if width_bits == 0 || width_bits >= 64 {
+            return;
+        }
+        let key = (index.stream_id, index.stream_instance_id);
+        let last = self.last.get(&key).copied();
+        index.timestamp_begin = match last {
+            Some(last) => extend(last, index.timestamp_begin, width_bits),
+            None => index.timestamp_begin,
+        };
+        index.timestamp_end = extend(index.timestamp_begin, index.timestamp_end, width_bits);
+        self.last.insert(key, index.timestamp_end);
+    }
+}
+
+/// The smallest value not less than `last` whose low `width_bits` bits are `value`
+fn extend(last: u64, value: u64, width_bits: u64) -> u64 {
+    let mask = (1 << width_bits) - 1;
+    let extended = (last & !mask) | (value & mask);
+    if extended < last {
+        extended.wrapping_add(1 << width_bits)
+    } else {
+        extended
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU64;
+
+    fn index(stream_instance_id: u64, begin: u64, end: u64) -> Index {
+        Index {
+            packet_size_bits: NonZeroU64::new(8).unwrap(),
+            content_size_bits: 8,
+            timestamp_begin: begin,
+            timestamp_end: end,
+            events_discarded: OptionalIndexField::none(),
+            stream_id: 0,
+            stream_instance_id: OptionalIndexField::new(stream_instance_id),
+            packet_seq_num: OptionalIndexField::none(),
+        }
+    }
+
+    #[test]
+    fn extend_wrapped_timestamps() {
+        let mut ext = TimestampExtender::default();
+        let mut packets = [
+            index(0, 0xFFFF_FF00, 0xFFFF_FFF0),
+            // Wraps within the packet
+            index(0, 0xFFFF_FFF8, 0x0000_0010),
+            // Another stream has its own history
+            index(1, 0x0000_0020, 0x0000_0030),
+            index(0, 0x0000_0020, 0x0000_0030),
+        ];
+        for pkt in packets.iter_mut() {
+            ext.extend(32, pkt);
+        }
+        let ts: Vec<(u64, u64)> = packets
+            .iter()
+            .map(|p| (p.timestamp_begin, p.timestamp_end))
+            .collect();
+        assert_eq!(
+            ts,
+            vec![
+                (0xFFFF_FF00, 0xFFFF_FFF0),
+                (0xFFFF_FFF8, 0x1_0000_0010),
+                (0x20, 0x30),
+                (0x1_0000_0020, 0x1_0000_0030),
+            ]
+        );
+    }
+}
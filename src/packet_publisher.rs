@@ -1,100 +1,189 @@
+use crate::console::{ConsoleOutput, ConsoleSink, CONSOLE_CHANNEL_CAPACITY};
+use crate::metadata::{ClockOffset, MetadataSet};
 use crate::packet::{CtfPacket, CtfPacketCodec, DecoderError};
-use crate::serial::{self, DeviceOpts};
-use crate::DeviceOrSocket;
-use futures::stream::Stream;
-use futures::stream::StreamExt;
-use std::{
-    collections::BTreeSet,
-    io,
-    path::Path,
-    pin::Pin,
-    task::{Context, Poll},
-};
+use crate::serial::{DeviceOpts, HotPlugDevice};
+use crate::stream_mapping::StreamSelector;
+use crate::udp::{self, CodecFactory, PeerDecoder, UdpSource};
+use crate::{DeviceOrSocket, PacketSource};
+use bytes::BytesMut;
+use futures::stream::{self, Stream, StreamExt, TryStreamExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{io, pin::Pin, sync::Arc};
 use thiserror::Error;
 use tokio::net::UdpSocket;
-use tokio::sync::mpsc;
-use tokio_util::codec::Decoder;
-use tokio_util::udp::UdpFramed;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_serial::SerialStream;
+use tokio_util::codec::{Decoder, Framed};
 use tracing::{debug, info, warn};
 
+#[derive(Clone)]
 pub struct PacketPublisherConfig {
-    /// Packets with any of these stream IDs will
+    /// Packets matching the selector will
     /// be sent on the channel
-    pub stream_ids: BTreeSet<u64>,
+    pub selector: StreamSelector,
     /// The channel matching packets will be sent on
     pub sender: mpsc::Sender<CtfPacket>,
+    /// The mirroring relayd sessions, matching packets are
+    /// sent on them when there's room
+    pub mirrors: Vec<MirrorSender>,
+}
+
+/// The channel of a mirroring relayd session.
+/// Mirrors are best-effort, a packet is dropped when the mirror lags behind.
+#[derive(Clone)]
+pub struct MirrorSender {
+    /// Names the mirror in the logs
+    name: Arc<String>,
+    sender: mpsc::Sender<CtfPacket>,
+    /// Shared by the sources sending to the mirror
+    dropped: Arc<AtomicU64>,
+}
+
+impl MirrorSender {
+    /// `dropped` counts the packets dropped because the mirror's channel was full
+    pub fn new(name: String, sender: mpsc::Sender<CtfPacket>, dropped: Arc<AtomicU64>) -> Self {
+        Self {
+            name: Arc::new(name),
+            sender,
+            dropped,
+        }
+    }
+
+    fn send(&self, pkt: CtfPacket) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(pkt) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped == 1 {
+                warn!(
+                    "Dropping packets for the {}, it's lagging behind",
+                    self.name
+                );
+            } else {
+                debug!("Dropped a packet for the {}, {} so far", self.name, dropped);
+            }
+        }
+    }
 }
 
 impl PacketPublisherConfig {
-    fn sender(&self, stream_id: u64) -> Option<&mpsc::Sender<CtfPacket>> {
-        // Empty set means all IDs are accepted on this channel
-        if self.stream_ids.is_empty() || self.stream_ids.contains(&stream_id) {
-            Some(&self.sender)
-        } else {
-            None
+    fn matches(&self, pkt: &CtfPacket, source: &PacketSource) -> bool {
+        self.selector.matches(
+            pkt.index.stream_id,
+            pkt.index.stream_instance_id.get(),
+            source,
+        )
+    }
+
+    async fn publish(&self, pkt: CtfPacket) -> Result<(), Error> {
+        // A failed or lagging mirror doesn't hold up the primary
+        for mirror in self.mirrors.iter() {
+            mirror.send(pkt.clone());
         }
+        self.sender
+            .send(pkt)
+            .await
+            .map_err(|_| Error::ReceiverClosed)
     }
 }
 
 #[derive(Debug, Error)]
 pub enum Error {
-    #[error("Encountered end of stream unexpectedly")]
-    EndOfStream,
-
     #[error("The packet receiver has shutdown")]
     ReceiverClosed,
 
     #[error("Socket setup problem. {0}")]
     SocketSetup(io::Error),
+
+    #[error("Failed to open the console output {0}. {1}")]
+    ConsoleSetup(ConsoleOutput, io::Error),
 }
 
+type PacketStream =
+    Pin<Box<dyn Stream<Item = Result<(CtfPacket, PacketSource), DecoderError>> + Send>>;
+
 /// Value chosen "empirically" to reduce the odds of
 /// dropping unprocessed frames on the floor
 const SOCKET_RECV_BUF_SIZE: usize = 25_000_000;
 
-pub async fn run_packet_publisher<P: AsRef<Path>>(
+pub async fn run_packet_publisher(
     source: DeviceOrSocket,
     device_opts: DeviceOpts,
-    metadata_file: P,
+    metadata: Option<watch::Receiver<MetadataSet>>,
+    clock_offset: Option<ClockOffset>,
+    fan_out: bool,
     channel_configs: Vec<PacketPublisherConfig>,
+    shutdown: broadcast::Receiver<()>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    let mut reader: Pin<Box<dyn Stream<Item = Result<CtfPacket, DecoderError>> + Send>> =
-        match source {
-            DeviceOrSocket::Device(d) => {
-                let src = serial::open(&d, &device_opts)?;
-                Box::pin(CtfPacketCodec::new(&metadata_file, &Default::default())?.framed(src))
-            }
-            DeviceOrSocket::UdpSocket(a) => {
-                info!("Binding to {}", a);
-                let socket = std::net::UdpSocket::bind(a).map_err(Error::SocketSetup)?;
-                socket.set_nonblocking(true).map_err(Error::SocketSetup)?;
-                // Switch into socket2 representation to fiddle with the recv_buffer_size,
-                // which is not exposed in the standard `UdpSocket`
-                let socket = socket2::Socket::from(socket);
-                if let Ok(old_size) = socket.recv_buffer_size() {
-                    if old_size < SOCKET_RECV_BUF_SIZE {
-                        if let Err(e) = socket.set_recv_buffer_size(SOCKET_RECV_BUF_SIZE) {
-                            warn!("Could not increase the UDP socket's recv buffer size to {}. Assume previously established size of {} remains. {}",
-                      SOCKET_RECV_BUF_SIZE, old_size, e);
-                        }
-                    }
-                } else if let Err(e) = socket.set_recv_buffer_size(SOCKET_RECV_BUF_SIZE) {
-                    warn!(
-                        "Could not set the UDP socket's recv buffer size to {}. {}",
-                        SOCKET_RECV_BUF_SIZE, e
-                    );
+    let console = match &device_opts.console {
+        Some(output) => {
+            info!("Forwarding the console of '{}' to {}", source, output);
+            let sink = ConsoleSink::open(output)
+                .await
+                .map_err(|e| Error::ConsoleSetup(output.clone(), e))?;
+            let (tx, rx) = mpsc::channel(CONSOLE_CHANNEL_CAPACITY);
+            tokio::spawn(async move {
+                if let Err(e) = sink.run(rx).await {
+                    warn!("Console forwarding stopped. {}", e);
                 }
-                let socket = UdpSocket::from_std(socket.into()).map_err(Error::SocketSetup)?;
-                Box::pin(UdpFramedWithoutSrcAddr {
-                    s: UdpFramed::new(
-                        socket,
-                        CtfPacketCodec::new(&metadata_file, &Default::default())?,
-                    ),
-                })
+            });
+            Some(tx)
+        }
+        None => None,
+    };
+    if metadata.is_none() {
+        info!("Waiting for in-band metadata");
+    }
+    let framing = device_opts.framing;
+    // UDP sources make one per peer
+    let new_codec = move || -> Result<CtfPacketCodec, DecoderError> {
+        let mut codec = match &metadata {
+            Some(updates) => {
+                let md = updates.borrow().clone();
+                CtfPacketCodec::new(&md, &Default::default())?
+                    .with_metadata_updates(updates.clone())
             }
+            None => CtfPacketCodec::with_in_band_metadata(&Default::default()),
         };
+        if let Some(offset) = clock_offset {
+            codec = codec.with_clock_offset(offset);
+        }
+        codec = codec.with_framing(framing);
+        if let Some(tx) = &console {
+            codec = codec.with_console(tx.clone());
+        }
+        Ok(codec)
+    };
+    let mut reader: PacketStream = match source {
+        DeviceOrSocket::Device(d) => {
+            let (device, port) = HotPlugDevice::open(&d, &device_opts)?;
+            hot_plug_packets(device, device_opts, new_codec()?.framed(port), shutdown)
+        }
+        DeviceOrSocket::UsbDevice(u) => {
+            let (device, port) = HotPlugDevice::open_usb(&u, &device_opts)?;
+            hot_plug_packets(device, device_opts, new_codec()?.framed(port), shutdown)
+        }
+        DeviceOrSocket::UdpSocket(u) => {
+            let socket = if u.is_multicast() {
+                match &u.interface {
+                    Some(i) => info!("Joining multicast group {} on interface {}", u.addr, i),
+                    None => info!("Joining multicast group {}", u.addr),
+                }
+                udp::multicast_socket(u.addr, u.interface.as_ref()).map_err(Error::SocketSetup)?
+            } else {
+                info!("Binding to {}", u.addr);
+                std::net::UdpSocket::bind(u.addr)
+                    .map_err(Error::SocketSetup)?
+                    .into()
+            };
+            if !u.allowed_peers.is_empty() {
+                let peers: Vec<String> = u.allowed_peers.iter().map(|p| p.to_string()).collect();
+                info!("Accepting datagrams from {}", peers.join(", "));
+            }
+            udp_packets(socket, u, Box::new(new_codec), shutdown)?
+        }
+    };
     while let Some(pkt_result) = reader.next().await {
-        let pkt = match pkt_result {
+        let (pkt, source) = match pkt_result {
             Ok(p) => p,
             Err(e) => {
                 warn!("Packet codec returned an error. {}", e);
@@ -103,31 +192,224 @@ pub async fn run_packet_publisher<P: AsRef<Path>>(
         };
         debug!("{pkt}");
 
-        if let Some(sender) = channel_configs
-            .iter()
-            .find_map(|c| c.sender(pkt.index.stream_id))
-        {
-            sender.send(pkt).await.map_err(|_| Error::ReceiverClosed)?;
+        let matching: Vec<_> = if fan_out {
+            channel_configs
+                .iter()
+                .filter(|c| c.matches(&pkt, &source))
+                .collect()
+        } else {
+            channel_configs
+                .iter()
+                .find(|c| c.matches(&pkt, &source))
+                .into_iter()
+                .collect()
+        };
+        if let Some((last, rest)) = matching.split_last() {
+            for cfg in rest {
+                cfg.publish(pkt.clone()).await?;
+            }
+            last.publish(pkt).await?;
         } else {
             debug!("Dropping packet because it has no receiver mapped");
         }
     }
 
-    // This tasks never completes nor handles shutdowns
-    Err(Error::EndOfStream.into())
+    // The stream only ends once shutdown and drained
+    debug!("Drained the source");
+    Ok(())
+}
+
+fn udp_packets(
+    socket: socket2::Socket,
+    source: UdpSource,
+    new_codec: CodecFactory,
+    shutdown: broadcast::Receiver<()>,
+) -> Result<PacketStream, Error> {
+    socket.set_nonblocking(true).map_err(Error::SocketSetup)?;
+    // The socket2 representation exposes the recv_buffer_size,
+    // which the standard `UdpSocket` doesn't
+    if let Ok(old_size) = socket.recv_buffer_size() {
+        if old_size < SOCKET_RECV_BUF_SIZE {
+            if let Err(e) = socket.set_recv_buffer_size(SOCKET_RECV_BUF_SIZE) {
+                warn!("Could not increase the UDP socket's recv buffer size to {}. Assume previously established size of {} remains. {}",
+                      SOCKET_RECV_BUF_SIZE, old_size, e);
+            }
+        }
+    } else if let Err(e) = socket.set_recv_buffer_size(SOCKET_RECV_BUF_SIZE) {
+        warn!(
+            "Could not set the UDP socket's recv buffer size to {}. {}",
+            SOCKET_RECV_BUF_SIZE, e
+        );
+    }
+    let socket = UdpSocket::from_std(socket.into()).map_err(Error::SocketSetup)?;
+    let decoder = PeerDecoder::new(socket, source, new_codec, shutdown);
+    let packets = stream::unfold(decoder, |mut decoder| async move {
+        let res = decoder.next().await?;
+        Some((res, decoder))
+    });
+    Ok(Box::pin(
+        packets.map_ok(|(p, addr)| (p, PacketSource::Udp(addr))),
+    ))
+}
+
+enum DeviceState {
+    /// `errored` is set when the last item was a decoder error, after which
+    /// the `Framed` returns None once without the device having gone away
+    Open {
+        framed: Framed<SerialStream, CtfPacketCodec>,
+        errored: bool,
+    },
+    Unplugged(CtfPacketCodec),
+    /// Shutting down, decoding the bytes already read
+    Draining(CtfPacketCodec, BytesMut),
 }
 
-pub struct UdpFramedWithoutSrcAddr {
-    s: UdpFramed<CtfPacketCodec, UdpSocket>,
+/// Reopens the device when it goes away, keeping the codec's metadata and
+/// timestamp state so the sessions carry on.
+/// The stream ends on shutdown, once the packets already read are decoded.
+fn hot_plug_packets(
+    device: HotPlugDevice,
+    device_opts: DeviceOpts,
+    framed: Framed<SerialStream, CtfPacketCodec>,
+    shutdown: broadcast::Receiver<()>,
+) -> PacketStream {
+    let source = PacketSource::Device(device.name().to_string());
+    let device = Arc::new((device, device_opts));
+    let init = (
+        DeviceState::Open {
+            framed,
+            errored: false,
+        },
+        shutdown,
+    );
+    let packets = stream::unfold(init, move |(mut state, mut shutdown)| {
+        let device = device.clone();
+        async move {
+            loop {
+                state = match state {
+                    DeviceState::Open {
+                        mut framed,
+                        errored,
+                    } => {
+                        let next = tokio::select! {
+                            _ = shutdown.recv() => None,
+                            next = framed.next() => Some(next),
+                        };
+                        match next {
+                            None => {
+                                let parts = framed.into_parts();
+                                DeviceState::Draining(parts.codec, parts.read_buf)
+                            }
+                            Some(Some(Err(DecoderError::Io(e)))) => {
+                                warn!(
+                                    "Serial device '{}' read failed, waiting for it to come back. {}",
+                                    device.0.name(),
+                                    e
+                                );
+                                DeviceState::Unplugged(framed.into_parts().codec)
+                            }
+                            Some(None) if errored => {
+                                // Keep the bytes already read, the rebuilt `Framed`
+                                // decodes them before reading more
+                                DeviceState::Open {
+                                    framed: Framed::from_parts(framed.into_parts()),
+                                    errored: false,
+                                }
+                            }
+                            Some(None) => {
+                                warn!(
+                                    "Serial device '{}' closed, waiting for it to come back",
+                                    device.0.name()
+                                );
+                                DeviceState::Unplugged(framed.into_parts().codec)
+                            }
+                            Some(Some(res)) => {
+                                let errored = res.is_err();
+                                return Some((
+                                    res,
+                                    (DeviceState::Open { framed, errored }, shutdown),
+                                ));
+                            }
+                        }
+                    }
+                    DeviceState::Unplugged(codec) => {
+                        let (device, device_opts) = &*device;
+                        // Partial packets from before the gap were dropped along with the buffer
+                        tokio::select! {
+                            _ = shutdown.recv() => {
+                                codec.report_drops(&device.name());
+                                return None;
+                            }
+                            port = device.reopen(device_opts) => DeviceState::Open {
+                                framed: codec.framed(port),
+                                errored: false,
+                            },
+                        }
+                    }
+                    DeviceState::Draining(mut codec, mut buf) => {
+                        let res = match codec.decode(&mut buf) {
+                            Ok(Some(p)) => Ok(p),
+                            Ok(None) => {
+                                codec.report_drops(&device.0.name());
+                                return None;
+                            }
+                            Err(e) => Err(e),
+                        };
+                        return Some((res, (DeviceState::Draining(codec, buf), shutdown)));
+                    }
+                };
+            }
+        }
+    });
+    Box::pin(packets.map_ok(move |p| (p, source.clone())))
 }
 
-impl Unpin for UdpFramedWithoutSrcAddr {}
+#[cfg(all(test, unix, feature = "pure-rust"))]
+mod tests {
+    use super::*;
+    use crate::metadata::tsdl::tests::tsdl;
+    use crate::metadata::Metadata;
+    use std::time::Duration;
+    use tokio::io::AsyncWriteExt;
+    use tokio_serial::SerialPort;
+    use uuid::Uuid;
+
+    fn packet(stream_id: u32, seq_num: u64) -> Vec<u8> {
+        let uuid = Uuid::parse_str("79e49040-21b5-42d4-a873-677261696e65").unwrap();
+        let mut pkt = Vec::new();
+        pkt.extend_from_slice(&0xC1FC_1FC1_u32.to_le_bytes());
+        pkt.extend_from_slice(uuid.as_bytes());
+        pkt.extend_from_slice(&stream_id.to_le_bytes());
+        pkt.extend_from_slice(&7_u64.to_le_bytes());
+        for v in [100_u64, 200, 1024, 2048, seq_num, 0] {
+            pkt.extend_from_slice(&v.to_le_bytes());
+        }
+        pkt.resize(2048 / 8, 0);
+        pkt
+    }
+
+    #[tokio::test]
+    async fn hot_plug_skips_corrupt_packets() {
+        let (mut target, port) = SerialStream::pair().unwrap();
+        let path = port.name().unwrap();
+        let opts = DeviceOpts::default();
+        let (device, port) = HotPlugDevice::open(&path, &opts).unwrap();
+        let md = Arc::new(Metadata::from_tsdl(tsdl().into_bytes()).unwrap());
+        let codec = CtfPacketCodec::new(&md.into(), &Default::default()).unwrap();
+        let (_shutdown_tx, shutdown) = broadcast::channel(1);
+        let mut packets = hot_plug_packets(device, opts, codec.framed(port), shutdown);
 
-impl Stream for UdpFramedWithoutSrcAddr {
-    type Item = Result<CtfPacket, DecoderError>;
+        // Stream class 5 isn't in the metadata
+        let mut bytes = packet(5, 1);
+        bytes.extend_from_slice(&packet(0, 2));
+        target.write_all(&bytes).await.unwrap();
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let pin = self.get_mut();
-        Stream::poll_next(Pin::new(&mut pin.s), cx).map_ok(|(t, _addr)| t)
+        let (pkt, source) = tokio::time::timeout(Duration::from_secs(5), packets.next())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        assert_eq!(pkt.index.packet_seq_num.get(), Some(2));
+        assert_eq!(source, PacketSource::Device(path));
     }
 }
@@ -1,10 +1,14 @@
+use crate::metadata::Metadata;
 use crate::packet::CtfPacket;
-use crate::relayd::RelaydClient;
+use crate::relayd::wire::StreamId;
+use crate::relayd::{RelaydClient, TraceId};
 use std::collections::{btree_map::Entry, BTreeMap};
+use std::future::Future;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, warn};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
 
 pub struct PacketSubscriberConfig {
     pub control_port: SocketAddr,
@@ -13,7 +17,9 @@ pub struct PacketSubscriberConfig {
     pub session_name: String,
     pub pathname: String,
     pub live_timer: u32,
-    pub metadata_bytes: Arc<Vec<u8>>,
+    /// When not provided, the session is started with the metadata
+    /// of the first packet received
+    pub metadata: Option<Arc<Metadata>>,
     pub packet_receiver: mpsc::Receiver<CtfPacket>,
     pub shutdown_receiver: broadcast::Receiver<()>,
     pub shutdown_responder: mpsc::Sender<()>,
@@ -29,40 +35,127 @@ pub async fn run_packet_subscriber(
         session_name,
         pathname,
         live_timer,
-        metadata_bytes,
+        metadata,
         mut packet_receiver,
         mut shutdown_receiver,
         shutdown_responder: _,
     } = cfg;
 
-    let client = RelaydClient::new(&control_port, &data_port).await?;
-    let client = client
-        .create_session(&session_name, &hostname, live_timer)
-        .await?;
-    let mut client = client.start(&pathname, &metadata_bytes).await?;
+    // A relayd that doesn't answer mustn't hold up the shutdown,
+    // once it has answered the session is started regardless
+    let client = match unless_shutdown(
+        async {
+            RelaydClient::new(&control_port, &data_port)
+                .await?
+                .create_session(&session_name, &hostname, live_timer)
+                .await
+        },
+        &mut shutdown_receiver,
+    )
+    .await
+    {
+        Some(res) => res?,
+        None => return Ok(()),
+    };
+    let (mut client, first_trace, mut first_pkt) = match metadata {
+        Some(md) => {
+            let (client, id) = client.start(&pathname, md.as_bytes()).await?;
+            (client, SessionTrace::new(id, md), None)
+        }
+        None => {
+            debug!("Waiting for the first packet to start the session");
+            match next_packet(&mut packet_receiver, &mut shutdown_receiver).await {
+                Some(pkt) => {
+                    let (client, id) = client.start(&pathname, pkt.metadata.as_bytes()).await?;
+                    (
+                        client,
+                        SessionTrace::new(id, pkt.metadata.clone()),
+                        Some(pkt),
+                    )
+                }
+                None => return Ok(()),
+            }
+        }
+    };
 
-    let mut stream_class_ids_to_stream_ids = BTreeMap::new();
+    // Packets of different firmware don't share a trace, each trace UUID
+    // gets its own metadata and streams in another directory
+    let mut traces: BTreeMap<Option<Uuid>, SessionTrace> = BTreeMap::new();
+    traces.insert(first_trace.metadata.trace_uuid(), first_trace);
+    let mut trace_generation = 0;
 
     loop {
-        let pkt = tokio::select! {
-            _ = shutdown_receiver.recv() => {
-                debug!("Shutting down");
-                let _client = client.close_streams().await?;
-                return Ok(())
-            }
-            maybe_pkt = packet_receiver.recv() => match maybe_pkt {
+        let pkt = match first_pkt.take() {
+            Some(pkt) => pkt,
+            None => match next_packet(&mut packet_receiver, &mut shutdown_receiver).await {
                 Some(pkt) => pkt,
                 None => {
-                warn!("Shutting down unexpectedly");
-                let _client = client.close_streams().await?;
-                return Ok(())
+                    let _client = client.close_streams().await?;
+                    return Ok(());
                 }
+            },
+        };
+
+        let trace_uuid = pkt.metadata.trace_uuid();
+        let trace = match traces.entry(trace_uuid) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                trace_generation += 1;
+                let new_pathname = format!("{pathname}-{trace_generation}");
+                info!(
+                    "Received packets with trace UUID {}, starting its streams in '{}'",
+                    trace_uuid.unwrap_or_default(),
+                    new_pathname
+                );
+                let id = client
+                    .add_trace(&new_pathname, pkt.metadata.as_bytes())
+                    .await?;
+                entry.insert(SessionTrace::new(id, pkt.metadata.clone()))
             }
         };
 
-        let stream_id = match stream_class_ids_to_stream_ids.entry(pkt.index.stream_id) {
+        if !Arc::ptr_eq(&pkt.metadata, &trace.metadata) {
+            if pkt.metadata.as_bytes() != trace.metadata.as_bytes() {
+                match pkt
+                    .metadata
+                    .as_bytes()
+                    .strip_prefix(trace.metadata.as_bytes())
+                {
+                    Some(appended) => {
+                        info!("Appending {} bytes of metadata", appended.len());
+                        client.append_metadata(trace.id, appended).await?;
+                    }
+                    None => {
+                        // relayd only supports appending to the metadata stream,
+                        // start over with a new set of streams in another directory
+                        trace_generation += 1;
+                        let new_pathname = format!("{pathname}-{trace_generation}");
+                        warn!(
+                            "The metadata was replaced, restarting the streams in '{}'",
+                            new_pathname
+                        );
+                        client.close_trace(trace.id).await?;
+                        let id = client
+                            .add_trace(&new_pathname, pkt.metadata.as_bytes())
+                            .await?;
+                        *trace = SessionTrace::new(id, pkt.metadata.clone());
+                    }
+                }
+            }
+            trace.metadata = pkt.metadata.clone();
+        }
+
+        // Each data stream instance of a class gets its own relayd stream
+        let stream_key = (pkt.index.stream_id, pkt.index.stream_instance_id.get());
+        let stream_id = match trace.streams.entry(stream_key) {
             Entry::Vacant(entry) => {
-                let stream_id = client.add_data_stream(pkt.index.stream_id).await?;
+                let stream_id = client
+                    .add_data_stream(
+                        trace.id,
+                        pkt.index.stream_id,
+                        pkt.index.stream_instance_id.get(),
+                    )
+                    .await?;
                 entry.insert(stream_id);
                 stream_id
             }
@@ -74,3 +167,52 @@ pub async fn run_packet_subscriber(
             .await?;
     }
 }
+
+/// The relayd trace a trace UUID's packets are sent to
+struct SessionTrace {
+    id: TraceId,
+    metadata: Arc<Metadata>,
+    /// Keyed by stream class ID and stream instance ID
+    streams: BTreeMap<(u64, Option<u64>), StreamId>,
+}
+
+impl SessionTrace {
+    fn new(id: TraceId, metadata: Arc<Metadata>) -> Self {
+        Self {
+            id,
+            metadata,
+            streams: BTreeMap::new(),
+        }
+    }
+}
+
+/// Returns None if the shutdown comes first
+async fn unless_shutdown<T>(
+    fut: impl Future<Output = T>,
+    shutdown_receiver: &mut broadcast::Receiver<()>,
+) -> Option<T> {
+    tokio::select! {
+        res = fut => Some(res),
+        _ = shutdown_receiver.recv() => {
+            debug!("Shutting down before relayd answered");
+            None
+        }
+    }
+}
+
+/// Returns None once the publishers are gone. On shutdown, that's after
+/// they've drained, so the packets queued are all received first.
+async fn next_packet(
+    packet_receiver: &mut mpsc::Receiver<CtfPacket>,
+    shutdown_receiver: &mut broadcast::Receiver<()>,
+) -> Option<CtfPacket> {
+    let maybe_pkt = packet_receiver.recv().await;
+    if maybe_pkt.is_none() {
+        if shutdown_receiver.try_recv().is_ok() {
+            debug!("Shutting down");
+        } else {
+            warn!("Shutting down unexpectedly");
+        }
+    }
+    maybe_pkt
+}
@@ -1,7 +1,7 @@
 use std::collections::BTreeMap;
-use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::{fmt, io};
 use thiserror::Error;
 use tokio::{io::AsyncWriteExt, net::TcpStream};
 use tracing::{debug, info};
@@ -29,6 +29,9 @@ pub enum RelaydClientError {
     #[error("Invalid stream id ({0})")]
     InvalidStreamId(StreamId),
 
+    #[error("Invalid trace id ({0})")]
+    InvalidTraceId(TraceId),
+
     #[error("IO error")]
     Io(#[from] io::Error),
 }
@@ -44,6 +47,22 @@ pub struct ActiveSessionState {
 }
 pub struct StreamableState {
     session_id: SessionId,
+    traces: BTreeMap<TraceId, TraceStreams>,
+    next_trace_id: TraceId,
+}
+
+/// A trace of the session, with its own metadata stream and directory
+#[repr(transparent)]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub struct TraceId(u64);
+
+impl fmt::Display for TraceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+struct TraceStreams {
     pathname: Arc<String>,
     metadata_stream: StreamId,
     data_streams: BTreeMap<StreamId, NetworkSequenceNumber>,
@@ -138,11 +157,12 @@ impl RelaydClient<ConnectedState> {
 }
 
 impl RelaydClient<ActiveSessionState> {
+    /// Starts the session with its first trace
     pub async fn start(
         mut self,
         pathname: &str,
         metadata_bytes: &[u8],
-    ) -> Result<RelaydClient<StreamableState>, RelaydClientError> {
+    ) -> Result<(RelaydClient<StreamableState>, TraceId), RelaydClientError> {
         info!(
             "Starting session, streams will be written into the '{}' directory",
             pathname
@@ -150,35 +170,20 @@ impl RelaydClient<ActiveSessionState> {
         let metadata_stream = self.add_stream("metadata", pathname).await?;
         self.send_metadata(metadata_stream, metadata_bytes).await?;
         self.send_start_data().await?;
-        Ok(RelaydClient {
-            state: StreamableState {
-                session_id: self.state.session_id,
-                pathname: Arc::new(pathname.to_string()),
-                metadata_stream,
-                data_streams: Default::default(),
+        let trace_id = TraceId(0);
+        let mut traces = BTreeMap::new();
+        traces.insert(trace_id, TraceStreams::new(pathname, metadata_stream));
+        Ok((
+            RelaydClient {
+                state: StreamableState {
+                    session_id: self.state.session_id,
+                    traces,
+                    next_trace_id: TraceId(1),
+                },
+                common: self.common,
             },
-            common: self.common,
-        })
-    }
-
-    async fn send_metadata(
-        &mut self,
-        stream_id: StreamId,
-        metadata_bytes: &[u8],
-    ) -> Result<(), RelaydClientError> {
-        self.common.buffer.clear();
-        ControlHeader::write(
-            &mut self.common.buffer,
-            Command::SendMetadata,
-            SendMetadata::wire_size(metadata_bytes.len()) as _,
-        )
-        .await?;
-        self.common
-            .buffer
-            .reserve(SendMetadata::wire_size(metadata_bytes.len()));
-        SendMetadata::write(&mut self.common.buffer, stream_id, metadata_bytes).await?;
-        self.write_control_buffer().await?;
-        Ok(())
+            trace_id,
+        ))
     }
 
     async fn send_start_data(&mut self) -> Result<(), RelaydClientError> {
@@ -192,53 +197,126 @@ impl RelaydClient<ActiveSessionState> {
     }
 }
 
+impl TraceStreams {
+    fn new(pathname: &str, metadata_stream: StreamId) -> Self {
+        Self {
+            pathname: Arc::new(pathname.to_string()),
+            metadata_stream,
+            data_streams: Default::default(),
+        }
+    }
+}
+
 impl RelaydClient<StreamableState> {
     pub async fn close_streams(
-        self,
+        mut self,
     ) -> Result<RelaydClient<ActiveSessionState>, RelaydClientError> {
-        let StreamableState {
-            session_id,
-            pathname: _,
-            metadata_stream,
-            data_streams,
-        } = self.state;
-
-        let mut new_client = RelaydClient {
-            state: ActiveSessionState { session_id },
+        let traces = std::mem::take(&mut self.state.traces);
+        for trace in traces.into_values() {
+            self.close_trace_streams(trace).await?;
+        }
+        Ok(RelaydClient {
+            state: ActiveSessionState {
+                session_id: self.state.session_id,
+            },
             common: self.common,
-        };
+        })
+    }
+
+    /// Add another trace to the session, its streams are written into the
+    /// `pathname` directory alongside the other traces
+    pub async fn add_trace(
+        &mut self,
+        pathname: &str,
+        metadata_bytes: &[u8],
+    ) -> Result<TraceId, RelaydClientError> {
+        info!(
+            "Adding a trace, streams will be written into the '{}' directory",
+            pathname
+        );
+        let metadata_stream = self.add_stream("metadata", pathname).await?;
+        self.send_metadata(metadata_stream, metadata_bytes).await?;
+        self.send_streams_sent().await?;
+        let trace_id = self.state.next_trace_id;
+        self.state.next_trace_id = TraceId(trace_id.0 + 1);
+        self.state
+            .traces
+            .insert(trace_id, TraceStreams::new(pathname, metadata_stream));
+        Ok(trace_id)
+    }
+
+    /// Close the streams of a trace, the other traces carry on
+    pub async fn close_trace(&mut self, trace_id: TraceId) -> Result<(), RelaydClientError> {
+        let trace = self
+            .state
+            .traces
+            .remove(&trace_id)
+            .ok_or(RelaydClientError::InvalidTraceId(trace_id))?;
+        self.close_trace_streams(trace).await
+    }
 
+    async fn close_trace_streams(&mut self, trace: TraceStreams) -> Result<(), RelaydClientError> {
         // Close all the data streams first
-        for (stream_id, net_seq_num) in data_streams.into_iter() {
+        for (stream_id, net_seq_num) in trace.data_streams.into_iter() {
             // Send the last net_seq_num sent
             let last_net_seq_num = net_seq_num.previous();
-            new_client.close_stream(stream_id, last_net_seq_num).await?;
+            self.close_stream(stream_id, last_net_seq_num).await?;
         }
 
         // Close the metadata stream
         // metadata was *not* packetized, so no seq num
-        new_client
-            .close_stream(metadata_stream, NetworkSequenceNumber::NONE)
-            .await?;
+        self.close_stream(trace.metadata_stream, NetworkSequenceNumber::NONE)
+            .await
+    }
 
-        Ok(new_client)
+    /// Send additional metadata on the trace's existing metadata stream,
+    /// relayd appends it to what was sent previously
+    pub async fn append_metadata(
+        &mut self,
+        trace_id: TraceId,
+        metadata_bytes: &[u8],
+    ) -> Result<(), RelaydClientError> {
+        let metadata_stream = self.trace(trace_id)?.metadata_stream;
+        self.send_metadata(metadata_stream, metadata_bytes).await
     }
 
+    /// Streams of the same class are told apart by their instance ID
     pub async fn add_data_stream(
         &mut self,
+        trace_id: TraceId,
         stream_class_id: u64,
+        stream_instance_id: Option<u64>,
     ) -> Result<StreamId, RelaydClientError> {
-        let stream_filename = format!("stream{}", stream_class_id);
-        let pathname = self.state.pathname.clone();
+        let stream_filename = match stream_instance_id {
+            Some(instance) => format!("stream{}_{}", stream_class_id, instance),
+            None => format!("stream{}", stream_class_id),
+        };
+        let pathname = self.trace(trace_id)?.pathname.clone();
         let stream_id = self.add_stream(&stream_filename, &pathname).await?;
-        self.state
-            .data_streams
-            .insert(stream_id, NetworkSequenceNumber::default());
+        if let Some(trace) = self.state.traces.get_mut(&trace_id) {
+            trace
+                .data_streams
+                .insert(stream_id, NetworkSequenceNumber::default());
+        }
         // Inform relayd we've got a new stream
         self.send_streams_sent().await?;
         Ok(stream_id)
     }
 
+    fn trace(&self, trace_id: TraceId) -> Result<&TraceStreams, RelaydClientError> {
+        self.state
+            .traces
+            .get(&trace_id)
+            .ok_or(RelaydClientError::InvalidTraceId(trace_id))
+    }
+
+    fn data_stream_mut(&mut self, stream_id: StreamId) -> Option<&mut NetworkSequenceNumber> {
+        self.state
+            .traces
+            .values_mut()
+            .find_map(|t| t.data_streams.get_mut(&stream_id))
+    }
+
     pub async fn send_indexed_data(
         &mut self,
         stream_id: StreamId,
@@ -246,14 +324,12 @@ impl RelaydClient<StreamableState> {
         data: &[u8],
     ) -> Result<(), RelaydClientError> {
         let net_seq_num = self
-            .state
-            .data_streams
-            .get(&stream_id)
+            .data_stream_mut(stream_id)
             .cloned()
             .ok_or(RelaydClientError::InvalidStreamId(stream_id))?;
         self.send_data(stream_id, net_seq_num, data).await?;
         self.send_index(stream_id, net_seq_num, index).await?;
-        if let Some(nsn) = self.state.data_streams.get_mut(&stream_id) {
+        if let Some(nsn) = self.data_stream_mut(stream_id) {
             nsn.increment();
         }
         Ok(())
@@ -315,6 +391,26 @@ impl<S: RelaydClientState> RelaydClient<S> {
         Ok(())
     }
 
+    async fn send_metadata(
+        &mut self,
+        stream_id: StreamId,
+        metadata_bytes: &[u8],
+    ) -> Result<(), RelaydClientError> {
+        self.common.buffer.clear();
+        ControlHeader::write(
+            &mut self.common.buffer,
+            Command::SendMetadata,
+            SendMetadata::wire_size(metadata_bytes.len()) as _,
+        )
+        .await?;
+        self.common
+            .buffer
+            .reserve(SendMetadata::wire_size(metadata_bytes.len()));
+        SendMetadata::write(&mut self.common.buffer, stream_id, metadata_bytes).await?;
+        self.write_control_buffer().await?;
+        Ok(())
+    }
+
     async fn add_stream(
         &mut self,
         channel_name: &str,
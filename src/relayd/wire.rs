@@ -65,8 +65,9 @@ impl NetworkSequenceNumber {
         self.0 = self.0.saturating_add(1);
     }
 
+    /// NONE when nothing was sent yet
     pub fn previous(&self) -> Self {
-        Self(self.0.saturating_sub(1))
+        self.0.checked_sub(1).map(Self).unwrap_or(Self::NONE)
     }
 }
 
@@ -94,8 +95,9 @@ pub struct Index {
 
 impl fmt::Display for Index {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{{stream_id={}, packet_size={}, content_size={}, clock_begin={}, clock_end={}, discarded={}, seq_num={}}}",
+        write!(f, "{{stream_id={}, stream_instance_id={}, packet_size={}, content_size={}, clock_begin={}, clock_end={}, discarded={}, seq_num={}}}",
             self.stream_id,
+            self.stream_instance_id,
             self.packet_size_bits,
             self.content_size_bits,
             self.timestamp_begin,
@@ -120,6 +122,14 @@ impl OptionalIndexField {
     pub const fn new(value: u64) -> Self {
         Self(value)
     }
+
+    pub const fn get(&self) -> Option<u64> {
+        if self.0 == u64::MAX {
+            None
+        } else {
+            Some(self.0)
+        }
+    }
 }
 
 impl From<Option<u64>> for OptionalIndexField {
@@ -1,44 +1,398 @@
+use crate::console::ConsoleOutput;
+use crate::packet::Framing;
 use derive_more::{From, Into};
-use std::path::Path;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 use structopt::{clap, StructOpt};
 use thiserror::Error;
-use tokio_serial::{ClearBuffer, SerialPort, SerialPortBuilderExt, SerialStream};
-use tracing::info;
+use tokio_serial::{
+    ClearBuffer, SerialPort, SerialPortBuilderExt, SerialPortInfo, SerialPortType, SerialStream,
+    UsbPortInfo,
+};
+use tracing::{debug, info, warn};
 
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("The serial device '{0}' doesn't exist")]
     NonExistingDevice(String),
 
+    #[error("No USB serial device matches '{0}'")]
+    NoUsbDevice(String),
+
+    #[error("Several USB serial devices match '{0}', '{1}' and '{2}'. Select one with interface=<number>")]
+    AmbiguousUsbDevice(String, String, String),
+
+    #[error("The serial device '{0}' rejected the {1} setting. {2}")]
+    Rejected(String, String, String),
+
     #[error("Serial device error")]
     Serial(#[from] tokio_serial::Error),
 }
 
+/// How far the baud rate the port settled on can be from the requested one,
+/// in percent. UARTs usually tolerate a couple percents of mismatch.
+const BAUD_RATE_TOLERANCE_PERCENT: u64 = 2;
+
 pub fn open(device: &str, opts: &DeviceOpts) -> Result<SerialStream, Error> {
     info!(
-        "Opening '{}', baud_rate={}, data_bits={:?}, parity={:?}, stop_bits={:?}",
-        device, opts.baud_rate, opts.data_bits.0, opts.parity.0, opts.stop_bits.0
+        "Opening '{}', baud_rate={}, data_bits={:?}, parity={:?}, stop_bits={:?}, dtr={:?}, rts={:?}, exclusive={:?}",
+        device,
+        opts.baud_rate,
+        opts.data_bits.0,
+        opts.parity.0,
+        opts.stop_bits.0,
+        opts.dtr,
+        opts.rts,
+        opts.exclusive
     );
 
     if !Path::new(device).exists() {
         return Err(Error::NonExistingDevice(device.to_string()));
     }
 
+    let rejected = |setting: String, e: tokio_serial::Error| {
+        Error::Rejected(device.to_string(), setting, e.to_string())
+    };
+
+    // Non-standard rates are requested as-is, the driver picks the closest it can do
     let mut port = tokio_serial::new(device, opts.baud_rate)
         .data_bits(opts.data_bits.0)
         .flow_control(opts.flow_control.0)
         .parity(opts.parity.0)
         .stop_bits(opts.stop_bits.0)
         .open_native_async()?;
+    check_baud_rate(device, &port, opts.baud_rate)?;
+
+    // The OS asserts DTR and RTS when opening, so this can only
+    // shorten the pulse a target sees, on every reopen too
+    if let Some(level) = opts.dtr {
+        port.write_data_terminal_ready(level)
+            .map_err(|e| rejected(format!("dtr={}", level), e))?;
+    }
+    if let Some(level) = opts.rts {
+        port.write_request_to_send(level)
+            .map_err(|e| rejected(format!("rts={}", level), e))?;
+    }
     port.clear(ClearBuffer::All)?;
 
+    // The port is already opened exclusively unless told otherwise
     #[cfg(unix)]
-    port.set_exclusive(false)?;
+    if let Some(exclusive) = opts.exclusive {
+        port.set_exclusive(exclusive)
+            .map_err(|e| rejected(format!("exclusive={}", exclusive), e))?;
+    }
 
     Ok(port)
 }
 
+fn check_baud_rate(device: &str, port: &SerialStream, requested: u32) -> Result<(), Error> {
+    let actual = match port.baud_rate() {
+        Ok(b) => b,
+        Err(e) => {
+            debug!("Can't read back the baud rate of '{}'. {}", device, e);
+            return Ok(());
+        }
+    };
+    if actual == requested {
+        return Ok(());
+    }
+    let diff = u64::from(actual.abs_diff(requested));
+    if diff * 100 > u64::from(requested) * BAUD_RATE_TOLERANCE_PERCENT {
+        return Err(Error::Rejected(
+            device.to_string(),
+            format!("baud_rate={}", requested),
+            format!("The port runs at {} baud", actual),
+        ));
+    }
+    warn!(
+        "The serial device '{}' runs at {} baud instead of {}",
+        device, actual, requested
+    );
+    Ok(())
+}
+
+/// How often an unplugged device is looked for
+const REOPEN_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Where the stable links to the USB serial devices are
+const SERIAL_BY_ID_DIR: &str = "/dev/serial/by-id";
+
+/// A serial device that can be reopened after it's unplugged or reset
+///
+/// USB serial adapters can come back with another `/dev/ttyUSB<N>` name,
+/// so the device's `/dev/serial/by-id` link is preferred when it has one.
+#[derive(Debug, Clone)]
+pub struct HotPlugDevice {
+    name: String,
+    locator: Locator,
+}
+
+#[derive(Debug, Clone)]
+enum Locator {
+    Path {
+        path: String,
+        by_id: Option<PathBuf>,
+    },
+    Usb(UsbDeviceSelector),
+}
+
+impl HotPlugDevice {
+    /// Opens the device, it must be present
+    pub fn open(path: &str, opts: &DeviceOpts) -> Result<(Self, SerialStream), Error> {
+        let port = open(path, opts)?;
+        let by_id = by_id_link(Path::new(path));
+        if let Some(link) = &by_id {
+            debug!("Using '{}' to reopen '{}'", link.display(), path);
+        }
+        let dev = Self {
+            name: path.to_string(),
+            locator: Locator::Path {
+                path: path.to_string(),
+                by_id,
+            },
+        };
+        Ok((dev, port))
+    }
+
+    /// Opens the USB device matching the selector, it must be present
+    pub fn open_usb(
+        selector: &UsbDeviceSelector,
+        opts: &DeviceOpts,
+    ) -> Result<(Self, SerialStream), Error> {
+        let path = selector
+            .find()?
+            .ok_or_else(|| Error::NoUsbDevice(selector.to_string()))?;
+        let port = open(&path, opts)?;
+        let dev = Self {
+            name: selector.to_string(),
+            locator: Locator::Usb(selector.clone()),
+        };
+        Ok((dev, port))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits for the device to be present again and reopens it with the same options
+    pub async fn reopen(&self, opts: &DeviceOpts) -> SerialStream {
+        loop {
+            let candidates: Vec<String> = match &self.locator {
+                Locator::Path { path, by_id } => by_id
+                    .iter()
+                    .filter_map(|p| p.to_str())
+                    .chain(std::iter::once(path.as_str()))
+                    .map(str::to_string)
+                    .collect(),
+                Locator::Usb(selector) => match selector.find() {
+                    Ok(path) => path.into_iter().collect(),
+                    Err(e) => {
+                        debug!("Failed to look for '{}'. {}", self.name, e);
+                        Vec::new()
+                    }
+                },
+            };
+            for path in candidates {
+                if !Path::new(&path).exists() {
+                    continue;
+                }
+                match open(&path, opts) {
+                    Ok(port) => {
+                        info!("Reopened '{}'", self.name);
+                        return port;
+                    }
+                    // The device node can appear before it's usable
+                    Err(e) => debug!("Failed to reopen '{}'. {}", path, e),
+                }
+            }
+            tokio::time::sleep(REOPEN_POLL_INTERVAL).await;
+        }
+    }
+}
+
+/// The `/dev/serial/by-id` link to the device, if any
+fn by_id_link(device: &Path) -> Option<PathBuf> {
+    let device = fs::canonicalize(device).ok()?;
+    fs::read_dir(SERIAL_BY_ID_DIR)
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|link| fs::canonicalize(link).ok().as_ref() == Some(&device))
+}
+
+/// Selects a USB serial device by its descriptors, e.g. `vid=0483,pid=5740,serial=ABC123`
+///
+/// The IDs are hexadecimal, at least one of the fields is required.
+/// Adapters with several ports have one tty per USB interface, sharing the
+/// descriptors, `interface=<number>` picks one of them.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct UsbDeviceSelector {
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial_number: Option<String>,
+    /// The USB interface number, hexadecimal like the `-if<NN>` of the by-id links
+    pub interface: Option<u8>,
+}
+
+impl UsbDeviceSelector {
+    pub fn matches(&self, info: &UsbPortInfo) -> bool {
+        self.vid.is_none_or(|vid| vid == info.vid)
+            && self.pid.is_none_or(|pid| pid == info.pid)
+            && self
+                .serial_number
+                .as_ref()
+                .is_none_or(|sn| info.serial_number.as_ref() == Some(sn))
+    }
+
+    /// The path of the matching device, if it's present
+    pub fn find(&self) -> Result<Option<String>, Error> {
+        let mut found = tokio_serial::available_ports()?
+            .into_iter()
+            .filter_map(usb_port)
+            .filter(|(_, info)| self.matches(info))
+            .map(|(path, _)| path)
+            .filter(|path| {
+                self.interface
+                    .is_none_or(|i| interface_number(Path::new(path)) == Some(i))
+            });
+        let path = found.next();
+        if let Some(other) = found.next() {
+            return Err(Error::AmbiguousUsbDevice(
+                self.to_string(),
+                path.unwrap_or_default(),
+                other,
+            ));
+        }
+        Ok(path)
+    }
+}
+
+impl FromStr for UsbDeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err_msg = || {
+            format!(
+                "Invalid USB device '{}', use vid=<hex>,pid=<hex>,serial=<serial-number>,interface=<hex>",
+                s
+            )
+        };
+        let id =
+            |v: &str| u16::from_str_radix(v.trim_start_matches("0x"), 16).map_err(|_| err_msg());
+        let mut sel = UsbDeviceSelector::default();
+        for item in s.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match item.split_once('=').ok_or_else(err_msg)? {
+                ("vid", v) => sel.vid = Some(id(v)?),
+                ("pid", v) => sel.pid = Some(id(v)?),
+                ("serial", v) => sel.serial_number = Some(v.to_string()),
+                ("interface", v) => {
+                    sel.interface = Some(
+                        u8::from_str_radix(v.trim_start_matches("0x"), 16)
+                            .map_err(|_| err_msg())?,
+                    )
+                }
+                _ => return Err(err_msg()),
+            }
+        }
+        if sel == UsbDeviceSelector::default() {
+            return Err(err_msg());
+        }
+        Ok(sel)
+    }
+}
+
+impl fmt::Display for UsbDeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut fields = Vec::new();
+        if let Some(vid) = self.vid {
+            fields.push(format!("vid={:04x}", vid));
+        }
+        if let Some(pid) = self.pid {
+            fields.push(format!("pid={:04x}", pid));
+        }
+        if let Some(sn) = &self.serial_number {
+            fields.push(format!("serial={}", sn));
+        }
+        if let Some(i) = self.interface {
+            fields.push(format!("interface={:02x}", i));
+        }
+        write!(f, "serial:{}", fields.join(","))
+    }
+}
+
+/// The USB interface number of a serial device, from sysfs or its by-id link
+fn interface_number(device: &Path) -> Option<u8> {
+    #[cfg(target_os = "linux")]
+    if let Some(i) = sysfs_interface_number(device) {
+        return Some(i);
+    }
+    let link = by_id_link(device)?;
+    by_id_interface_number(link.file_name()?.to_str()?)
+}
+
+/// e.g. 2 for `usb-FTDI_Dual_RS232-HS-if02-port0`
+fn by_id_interface_number(link_name: &str) -> Option<u8> {
+    let (_, suffix) = link_name.rsplit_once("-if")?;
+    u8::from_str_radix(suffix.get(..2)?, 16).ok()
+}
+
+/// Reads `bInterfaceNumber` of the USB interface the tty belongs to
+#[cfg(target_os = "linux")]
+fn sysfs_interface_number(device: &Path) -> Option<u8> {
+    let name = fs::canonicalize(device).ok()?;
+    let name = name.file_name()?.to_str()?;
+    let mut dir = fs::canonicalize(Path::new("/sys/class/tty").join(name).join("device")).ok()?;
+    loop {
+        if let Ok(i) = fs::read_to_string(dir.join("bInterfaceNumber")) {
+            return u8::from_str_radix(i.trim(), 16).ok();
+        }
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+
+fn usb_port(port: SerialPortInfo) -> Option<(String, UsbPortInfo)> {
+    match port.port_type {
+        SerialPortType::UsbPort(info) => Some((port.port_name, info)),
+        // Without libudev, the ports are enumerated from sysfs with an unknown type
+        #[cfg(target_os = "linux")]
+        SerialPortType::Unknown => sysfs_usb_port(&port.port_name),
+        _ => None,
+    }
+}
+
+/// Reads the descriptors of the USB device a `/sys/class/tty/<name>` belongs to
+#[cfg(target_os = "linux")]
+fn sysfs_usb_port(sys_path: &str) -> Option<(String, UsbPortInfo)> {
+    let sys_path = Path::new(sys_path);
+    let name = sys_path.file_name()?.to_str()?;
+    let mut dir = fs::canonicalize(sys_path.join("device")).ok()?;
+    loop {
+        let read = |f: &str| {
+            fs::read_to_string(dir.join(f))
+                .ok()
+                .map(|s| s.trim().to_string())
+        };
+        if let (Some(vid), Some(pid)) = (read("idVendor"), read("idProduct")) {
+            let info = UsbPortInfo {
+                vid: u16::from_str_radix(&vid, 16).ok()?,
+                pid: u16::from_str_radix(&pid, 16).ok()?,
+                serial_number: read("serial"),
+                manufacturer: read("manufacturer"),
+                product: read("product"),
+            };
+            return Some((format!("/dev/{}", name), info));
+        }
+        if !dir.pop() {
+            return None;
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, StructOpt)]
 #[structopt(setting = clap::AppSettings::ColoredHelp)]
 pub struct DeviceOpts {
@@ -61,6 +415,42 @@ pub struct DeviceOpts {
     /// Serial device stop bits
     #[structopt(long, default_value = "1")]
     pub stop_bits: StopBits,
+
+    /// Framing the CTF packets are wrapped in: none, cobs, slip or hdlc.
+    /// HDLC frames carry a CRC-16, frames with a bad CRC are dropped.
+    #[structopt(long, default_value = "none")]
+    pub framing: Framing,
+
+    /// Forward the text found between the CTF packets, e.g. a boot log,
+    /// as lines to stdout ('-'), a file, or tcp://<addr> clients
+    #[structopt(long)]
+    pub console: Option<ConsoleOutput>,
+
+    /// Set DTR on (asserted) or off right after opening the device.
+    /// The OS asserts DTR and RTS when opening, so off only shortens the pulse
+    /// a target's reset line sees, it can't prevent it. Reopening an unplugged
+    /// device pulses it again.
+    #[structopt(long, parse(try_from_str = parse_on_off))]
+    pub dtr: Option<bool>,
+
+    /// Set RTS on (asserted) or off right after opening the device,
+    /// with the same caveat as DTR
+    #[structopt(long, parse(try_from_str = parse_on_off))]
+    pub rts: Option<bool>,
+
+    /// Whether other processes can't open the device meanwhile, on or off.
+    /// Unix devices are opened exclusively by default.
+    #[structopt(long, parse(try_from_str = parse_on_off))]
+    pub exclusive: Option<bool>,
+}
+
+/// Parses an on or off setting, e.g. a DTR or RTS level
+fn parse_on_off(s: &str) -> Result<bool, String> {
+    match s.trim().to_lowercase().as_str() {
+        "on" | "high" | "true" | "1" => Ok(true),
+        "off" | "low" | "false" | "0" => Ok(false),
+        _ => Err("Invalid setting, use on or off".to_string()),
+    }
 }
 
 impl Default for DeviceOpts {
@@ -71,6 +461,11 @@ impl Default for DeviceOpts {
             flow_control: tokio_serial::FlowControl::None.into(),
             parity: tokio_serial::Parity::None.into(),
             stop_bits: tokio_serial::StopBits::One.into(),
+            framing: Framing::None,
+            console: None,
+            dtr: None,
+            rts: None,
+            exclusive: None,
         }
     }
 }
@@ -138,3 +533,34 @@ impl FromStr for StopBits {
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usb_device_selectors() {
+        let sel: UsbDeviceSelector = "vid=403,pid=6010,serial=FT1234,interface=1"
+            .parse()
+            .unwrap();
+        assert_eq!(sel.vid, Some(0x0403));
+        assert_eq!(sel.pid, Some(0x6010));
+        assert_eq!(sel.serial_number.as_deref(), Some("FT1234"));
+        assert_eq!(sel.interface, Some(1));
+        assert_eq!(
+            sel.to_string(),
+            "serial:vid=0403,pid=6010,serial=FT1234,interface=01"
+        );
+        assert!("interface=foo".parse::<UsbDeviceSelector>().is_err());
+
+        assert_eq!(
+            by_id_interface_number("usb-FTDI_Dual_RS232-HS_FT1234-if01-port0"),
+            Some(1)
+        );
+        assert_eq!(
+            by_id_interface_number("usb-Silicon_Labs_CP2105_0042-if0a-port0"),
+            Some(10)
+        );
+        assert_eq!(by_id_interface_number("usb-STM_Virtual_COM"), None);
+    }
+}
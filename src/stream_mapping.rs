@@ -0,0 +1,441 @@
+use crate::serial::UsbDeviceSelector;
+use crate::PacketSource;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+use thiserror::Error;
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct StreamMapping {
+    /// Defaults to 'session'
+    pub session_name: String,
+    /// Defaults to 'trace'
+    pub pathname: String,
+    /// Defaults to matching all packets
+    pub selector: StreamSelector,
+    /// The first one is the primary, the others are best-effort mirrors.
+    /// Defaults to empty, meaning the global relayd
+    pub relayds: Vec<RelaydEndpoint>,
+}
+
+impl Default for StreamMapping {
+    fn default() -> Self {
+        Self {
+            session_name: "session".to_string(),
+            pathname: "trace".to_string(),
+            selector: Default::default(),
+            relayds: Default::default(),
+        }
+    }
+}
+
+impl FromStr for StreamMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err_msg =
+            "Invalid stream mapping format, use <session-name>:<pathname>:<stream-selectors>";
+        // The selectors come last since source addresses contain colons
+        let parts: Vec<&str> = s.trim().splitn(3, ':').collect();
+        if parts.len() != 3 || parts.iter().any(|p| p.is_empty()) {
+            return Err(err_msg.to_string());
+        }
+        let session_name = parts[0].to_string();
+        let pathname = expand_pathname(parts[1]);
+
+        let mut selectors = Vec::new();
+        let mut relayds = Vec::new();
+        for item in parts[2].split(',') {
+            match item.trim().strip_prefix("relayd=") {
+                Some(r) => relayds.push(r.parse().map_err(|e| format!("{}. {}", err_msg, e))?),
+                None => selectors.push(item),
+            }
+        }
+
+        Ok(Self {
+            session_name,
+            pathname,
+            selector: selectors
+                .join(",")
+                .parse()
+                .map_err(|e| format!("{}. {}", err_msg, e))?,
+            relayds,
+        })
+    }
+}
+
+/// Expands the $DATETIME keyword to the UTC datetime, in the format of YYYYmmdd-HHMMSS
+pub fn expand_pathname(pathname: &str) -> String {
+    if pathname.contains("$DATETIME") {
+        let now: DateTime<Utc> = Utc::now();
+        let datetime = now.format("%Y%m%d-%H%M%S").to_string();
+        pathname.replace("$DATETIME", &datetime)
+    } else {
+        pathname.to_string()
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum StreamMappingError {
+    #[error("Stream mapping for session '{0}' selects a stream ID ({1}) that is already mapped to session '{2}'")]
+    DuplicateStreamId(String, u64, String),
+
+    #[error("The session name '{0}' can only be used in a single stream mapping")]
+    DuplicateSessionName(String),
+}
+
+/// Checks that there are no overlapping stream IDs among the stream mappings, they must be
+/// exclusive unless fanning out. Same for duplicate session names.
+///
+/// Returns the index of the offending mapping along with the error.
+pub fn check_stream_mappings(
+    mappings: &[StreamMapping],
+    fan_out: bool,
+) -> Result<(), (usize, StreamMappingError)> {
+    let mut all_session_names = BTreeSet::new();
+    for (i, smap) in mappings.iter().enumerate() {
+        let prev_mappings = if fan_out { &[] } else { &mappings[..i] };
+        for prev in prev_mappings.iter() {
+            if let Some(id) = smap.selector.overlaps(&prev.selector) {
+                return Err((
+                    i,
+                    StreamMappingError::DuplicateStreamId(
+                        smap.session_name.clone(),
+                        id,
+                        prev.session_name.clone(),
+                    ),
+                ));
+            }
+        }
+
+        if !all_session_names.insert(&smap.session_name) {
+            return Err((
+                i,
+                StreamMappingError::DuplicateSessionName(smap.session_name.clone()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// An lttng-relayd control and data address pair
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RelaydEndpoint {
+    #[serde(rename = "control")]
+    pub control_port: SocketAddr,
+    #[serde(rename = "data")]
+    pub data_port: SocketAddr,
+}
+
+impl Default for RelaydEndpoint {
+    fn default() -> Self {
+        Self {
+            control_port: ([127, 0, 0, 1], 5342).into(),
+            data_port: ([127, 0, 0, 1], 5343).into(),
+        }
+    }
+}
+
+impl FromStr for RelaydEndpoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err_msg = || {
+            format!(
+                "Invalid relayd '{}', use <control-address:port>/<data-address:port>",
+                s
+            )
+        };
+        let (control, data) = s.split_once('/').ok_or_else(err_msg)?;
+        Ok(Self {
+            control_port: control.trim().parse().map_err(|_| err_msg())?,
+            data_port: data.trim().parse().map_err(|_| err_msg())?,
+        })
+    }
+}
+
+impl fmt::Display for RelaydEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.control_port, self.data_port)
+    }
+}
+
+/// Which packets a stream mapping receives
+///
+/// A packet matches when it matches every non-empty set of selectors,
+/// and any one selector within each set.
+#[derive(Debug, Clone, Eq, PartialEq, Default)]
+pub struct StreamSelector {
+    /// Stream class IDs, empty means all
+    pub stream_ids: Vec<RangeInclusive<u64>>,
+    /// Stream instance IDs, empty means all
+    pub stream_instance_ids: Vec<RangeInclusive<u64>>,
+    /// Packet sources, empty means all
+    pub sources: Vec<SourceSelector>,
+}
+
+impl StreamSelector {
+    pub fn matches(
+        &self,
+        stream_id: u64,
+        stream_instance_id: Option<u64>,
+        source: &PacketSource,
+    ) -> bool {
+        let instance_matches = match stream_instance_id {
+            Some(id) => self.stream_instance_ids.iter().any(|r| r.contains(&id)),
+            None => false,
+        };
+        (self.stream_ids.is_empty() || self.stream_ids.iter().any(|r| r.contains(&stream_id)))
+            && (self.stream_instance_ids.is_empty() || instance_matches)
+            && (self.sources.is_empty() || self.sources.iter().any(|s| s.matches(source)))
+    }
+
+    /// Whether both selectors explicitly select some of the same stream IDs and
+    /// could match the same packets
+    ///
+    /// Selectors matching any stream ID act as a fallback for the
+    /// other mappings, so they don't overlap.
+    pub fn overlaps(&self, other: &Self) -> Option<u64> {
+        let instances_overlap = self.stream_instance_ids.is_empty()
+            || other.stream_instance_ids.is_empty()
+            || ranges_overlap(&self.stream_instance_ids, &other.stream_instance_ids).is_some();
+        let sources_overlap = self.sources.is_empty()
+            || other.sources.is_empty()
+            || self
+                .sources
+                .iter()
+                .any(|a| other.sources.iter().any(|b| a.overlaps(b)));
+        if instances_overlap && sources_overlap {
+            ranges_overlap(&self.stream_ids, &other.stream_ids)
+        } else {
+            None
+        }
+    }
+}
+
+/// The smallest ID in both sets of ranges
+fn ranges_overlap(a: &[RangeInclusive<u64>], b: &[RangeInclusive<u64>]) -> Option<u64> {
+    a.iter()
+        .flat_map(|ra| {
+            b.iter().filter_map(move |rb| {
+                let start = *ra.start().max(rb.start());
+                (start <= *ra.end().min(rb.end())).then_some(start)
+            })
+        })
+        .min()
+}
+
+impl FromStr for StreamSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sel = StreamSelector::default();
+        let mut items = s
+            .split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .peekable();
+        while let Some(item) = items.next() {
+            if item == "ANY" {
+                continue;
+            } else if let Some(ids) = item.strip_prefix("instance=") {
+                sel.stream_instance_ids.push(parse_range(ids)?);
+            } else if let Some(src) = item.strip_prefix("source=") {
+                let mut src = src.to_string();
+                // The USB device fields are comma-separated too
+                if src.starts_with("serial:") {
+                    while let Some(field) = items.next_if(|i| {
+                        ["vid=", "pid=", "serial=", "interface="]
+                            .iter()
+                            .any(|f| i.starts_with(f))
+                    }) {
+                        src = format!("{},{}", src, field);
+                    }
+                }
+                sel.sources.push(src.parse()?);
+            } else {
+                sel.stream_ids.push(parse_range(item)?);
+            }
+        }
+        Ok(sel)
+    }
+}
+
+/// Either `<id>` or `<first>-<last>`
+pub(crate) fn parse_range(s: &str) -> Result<RangeInclusive<u64>, String> {
+    let parse = |s: &str| {
+        s.trim()
+            .parse::<u64>()
+            .map_err(|_| format!("Invalid stream ID '{}'", s))
+    };
+    let range = match s.split_once('-') {
+        Some((first, last)) => parse(first)?..=parse(last)?,
+        None => parse(s).map(|id| id..=id)?,
+    };
+    if range.is_empty() {
+        return Err(format!("Empty stream ID range '{}'", s));
+    }
+    Ok(range)
+}
+
+/// Matches the source a packet was received from
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum SourceSelector {
+    /// Serial device path
+    Device(String),
+    /// UDP peer address, with any port
+    Ip(IpAddr),
+    /// UDP peer address and port
+    Socket(SocketAddr),
+}
+
+impl SourceSelector {
+    pub fn matches(&self, source: &PacketSource) -> bool {
+        match (self, source) {
+            (SourceSelector::Device(a), PacketSource::Device(b)) => a == b,
+            (SourceSelector::Ip(a), PacketSource::Udp(b)) => *a == b.ip(),
+            (SourceSelector::Socket(a), PacketSource::Udp(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    fn overlaps(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SourceSelector::Ip(a), SourceSelector::Socket(b))
+            | (SourceSelector::Socket(b), SourceSelector::Ip(a)) => *a == b.ip(),
+            (a, b) => a == b,
+        }
+    }
+}
+
+impl FromStr for SourceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("file:") {
+            Ok(SourceSelector::Device(path.to_string()))
+        } else if let Some(usb) = s.strip_prefix("serial:") {
+            // Normalized to match the source's name
+            let usb: UsbDeviceSelector = usb.parse()?;
+            Ok(SourceSelector::Device(usb.to_string()))
+        } else if let Ok(a) = s.parse() {
+            Ok(SourceSelector::Socket(a))
+        } else if let Ok(a) = s.parse() {
+            Ok(SourceSelector::Ip(a))
+        } else if s.starts_with('/') {
+            Ok(SourceSelector::Device(s.to_string()))
+        } else {
+            Err(format!(
+                "Invalid source '{}', use a device path, a serial:<usb-device> or an address[:port]",
+                s
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn stream_mappings() {
+        assert_eq!(
+            StreamMapping::from_str("my-stream-a:trace-a:0,1,22,44").unwrap(),
+            StreamMapping {
+                session_name: "my-stream-a".to_owned(),
+                pathname: "trace-a".to_owned(),
+                selector: StreamSelector {
+                    stream_ids: vec![0..=0, 1..=1, 22..=22, 44..=44],
+                    ..Default::default()
+                },
+                relayds: Default::default(),
+            }
+        );
+
+        assert_eq!(
+            StreamMapping::from_str("my-stream-a:trace-a:ANY").unwrap(),
+            StreamMapping {
+                session_name: "my-stream-a".to_owned(),
+                pathname: "trace-a".to_owned(),
+                selector: Default::default(),
+                relayds: Default::default(),
+            }
+        );
+
+        let sm = StreamMapping::from_str("system-session:system=$DATETIME:1, 2, 4").unwrap();
+        assert_eq!(sm.session_name, "system-session".to_owned());
+        assert_eq!(sm.selector.stream_ids, vec![1..=1, 2..=2, 4..=4]);
+        let parts: Vec<&str> = sm.pathname.split('=').collect();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0], "system");
+        assert!(Utc.datetime_from_str(parts[1], "%Y%m%d-%H%M%S").is_ok());
+
+        let sm = StreamMapping::from_str("cpus:trace-cpus:0-15,instance=2-3,source=10.0.0.2:5000")
+            .unwrap();
+        assert_eq!(
+            sm.selector,
+            StreamSelector {
+                stream_ids: vec![0..=15],
+                stream_instance_ids: vec![2..=3],
+                sources: vec![SourceSelector::Socket("10.0.0.2:5000".parse().unwrap())],
+            }
+        );
+        let peer = PacketSource::Udp("10.0.0.2:5000".parse().unwrap());
+        assert!(sm.selector.matches(15, Some(2), &peer));
+        assert!(!sm.selector.matches(16, Some(2), &peer));
+        assert!(!sm.selector.matches(0, None, &peer));
+        assert!(!sm
+            .selector
+            .matches(0, Some(2), &PacketSource::Device("/dev/ttyUSB0".to_owned())));
+
+        let modem = StreamMapping::from_str("modem:trace-modem:ANY,source=/dev/ttyUSB1").unwrap();
+        assert!(modem.selector.stream_ids.is_empty());
+        assert_eq!(
+            modem.selector.sources,
+            vec![SourceSelector::Device("/dev/ttyUSB1".to_owned())]
+        );
+
+        let usb: StreamSelector = "ANY,source=serial:vid=483,pid=5740,serial=ABC123,instance=1"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            usb.sources,
+            vec![SourceSelector::Device(
+                "serial:vid=0483,pid=5740,serial=ABC123".to_owned()
+            )]
+        );
+        assert_eq!(usb.stream_instance_ids, vec![1..=1]);
+
+        let other: StreamSelector = "8,source=10.0.0.2".parse().unwrap();
+        assert_eq!(sm.selector.overlaps(&other), Some(8));
+        let other: StreamSelector = "8,source=/dev/ttyUSB0".parse().unwrap();
+        assert_eq!(sm.selector.overlaps(&other), None);
+
+        let sm = StreamMapping::from_str(
+            "mirrored:trace-m:1,relayd=10.0.0.5:5342/10.0.0.5:5343,relayd=[::1]:5342/[::1]:5343",
+        )
+        .unwrap();
+        assert_eq!(sm.selector.stream_ids, vec![1..=1]);
+        assert_eq!(
+            sm.relayds,
+            vec![
+                "10.0.0.5:5342/10.0.0.5:5343".parse().unwrap(),
+                RelaydEndpoint {
+                    control_port: "[::1]:5342".parse().unwrap(),
+                    data_port: "[::1]:5343".parse().unwrap(),
+                }
+            ]
+        );
+
+        assert!(StreamMapping::from_str("a:b:3-1").is_err());
+        assert!(StreamMapping::from_str("a:b:1,relayd=10.0.0.5:5342").is_err());
+        assert!(StreamMapping::from_str("a:b:source=foo").is_err());
+        assert!(StreamMapping::from_str("a::1").is_err());
+    }
+}
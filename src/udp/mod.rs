@@ -0,0 +1,563 @@
+//! UDP source sockets
+//!
+//! Each peer sending to a source gets its own packet codec, so their
+//! byte streams don't mix and packets can span datagrams.
+
+use crate::packet::{CtfPacket, CtfPacketCodec, DecoderError};
+use bytes::BytesMut;
+use reassembly::{Reassembler, ReassemblyOpts};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast;
+use tokio_util::codec::Decoder;
+use tracing::{debug, warn};
+use url::Url;
+
+pub mod reassembly;
+
+/// A UDP source, `udp://<addr>[?iface=<interface>][&allow=<peer>,...]`,
+/// `reassembly` reassembles the packets fragmented across datagrams
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UdpSource {
+    pub addr: SocketAddr,
+    /// The interface a multicast group is joined on
+    pub interface: Option<MulticastInterface>,
+    /// Datagrams from other peers are dropped, empty allows all
+    pub allowed_peers: Vec<PeerFilter>,
+    /// Set when the datagrams carry packet fragments
+    pub reassembly: Option<ReassemblyOpts>,
+}
+
+impl UdpSource {
+    pub fn from_url(url: &Url) -> Result<Self, String> {
+        let addrs = url
+            .socket_addrs(|| None)
+            .map_err(|e| format!("Failed to parse source URL. {}", e))?;
+        if addrs.len() != 1 {
+            return Err("Source URL contains multiple socket addresses.".to_string());
+        }
+        let mut src = UdpSource {
+            addr: addrs[0],
+            interface: None,
+            allowed_peers: Vec::new(),
+            reassembly: None,
+        };
+        for (k, v) in url.query_pairs() {
+            match k.as_ref() {
+                "iface" => src.interface = Some(v.parse()?),
+                "allow" => {
+                    for peer in v.split(',').filter(|p| !p.trim().is_empty()) {
+                        src.allowed_peers.push(peer.parse()?);
+                    }
+                }
+                "reassembly" => {
+                    src.reassembly = match v.as_ref() {
+                        "" | "on" | "true" | "1" => Some(src.reassembly.unwrap_or_default()),
+                        "off" | "false" | "0" => None,
+                        _ => return Err(format!("Invalid reassembly '{}', use on or off", v)),
+                    };
+                }
+                "reassembly_window" => {
+                    let window = v
+                        .parse()
+                        .ok()
+                        .filter(|w| *w > 0)
+                        .ok_or_else(|| format!("Invalid reassembly window '{}'", v))?;
+                    src.reassembly.get_or_insert_with(Default::default).window = window;
+                }
+                "reassembly_timeout" => {
+                    let ms = v
+                        .parse()
+                        .map_err(|_| format!("Invalid reassembly timeout '{}' ms", v))?;
+                    src.reassembly.get_or_insert_with(Default::default).timeout =
+                        Duration::from_millis(ms);
+                }
+                k => {
+                    return Err(format!(
+                        "Invalid parameter '{}' in source URL. Must be either 'iface', 'allow' or 'reassembly[_window|_timeout]'.",
+                        k
+                    ))
+                }
+            }
+        }
+        if src.interface.is_some() && !src.is_multicast() {
+            return Err("The 'iface' parameter only applies to multicast addresses.".to_string());
+        }
+        Ok(src)
+    }
+
+    pub fn is_multicast(&self) -> bool {
+        self.addr.ip().is_multicast()
+    }
+
+    pub fn allows(&self, peer: &SocketAddr) -> bool {
+        self.allowed_peers.is_empty() || self.allowed_peers.iter().any(|p| p.matches(peer))
+    }
+}
+
+impl fmt::Display for UdpSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "udp://{}", self.addr)?;
+        let mut params = Vec::new();
+        if let Some(i) = &self.interface {
+            params.push(format!("iface={}", i));
+        }
+        if !self.allowed_peers.is_empty() {
+            let peers: Vec<String> = self.allowed_peers.iter().map(|p| p.to_string()).collect();
+            params.push(format!("allow={}", peers.join(",")));
+        }
+        if let Some(r) = &self.reassembly {
+            let default = ReassemblyOpts::default();
+            params.push("reassembly".to_string());
+            if r.window != default.window {
+                params.push(format!("reassembly_window={}", r.window));
+            }
+            if r.timeout != default.timeout {
+                params.push(format!("reassembly_timeout={}", r.timeout.as_millis()));
+            }
+        }
+        if !params.is_empty() {
+            write!(f, "?{}", params.join("&"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A UDP peer, or all the peers with the address
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PeerFilter {
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl PeerFilter {
+    pub fn matches(&self, peer: &SocketAddr) -> bool {
+        match self {
+            PeerFilter::Ip(a) => *a == peer.ip(),
+            PeerFilter::Socket(a) => a == peer,
+        }
+    }
+}
+
+impl fmt::Display for PeerFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerFilter::Ip(a) => a.fmt(f),
+            PeerFilter::Socket(a) => a.fmt(f),
+        }
+    }
+}
+
+impl FromStr for PeerFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if let Ok(a) = s.parse() {
+            Ok(PeerFilter::Socket(a))
+        } else if let Ok(a) = s.parse() {
+            Ok(PeerFilter::Ip(a))
+        } else {
+            Err(format!("Invalid peer '{}', use an address[:port]", s))
+        }
+    }
+}
+
+/// The interface a multicast group is joined on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MulticastInterface {
+    /// e.g. "eth1"
+    Name(String),
+    Index(u32),
+    /// An IPv4 address of the interface
+    Address(Ipv4Addr),
+}
+
+impl MulticastInterface {
+    fn index(&self) -> io::Result<u32> {
+        match self {
+            MulticastInterface::Index(i) => Ok(*i),
+            MulticastInterface::Name(name) => interface_index(name),
+            MulticastInterface::Address(a) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "IPv6 multicast groups need an interface name or index, not the address {}",
+                    a
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    let c_name =
+        std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: c_name is a valid NUL terminated string
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No network interface named '{}'", name),
+        )),
+        idx => Ok(idx),
+    }
+}
+
+#[cfg(not(unix))]
+fn interface_index(name: &str) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "Interface names aren't supported on this platform, use the index of '{}'",
+            name
+        ),
+    ))
+}
+
+impl fmt::Display for MulticastInterface {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MulticastInterface::Name(n) => f.write_str(n),
+            MulticastInterface::Index(i) => i.fmt(f),
+            MulticastInterface::Address(a) => a.fmt(f),
+        }
+    }
+}
+
+impl FromStr for MulticastInterface {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            Err("Empty multicast interface".to_string())
+        } else if let Ok(a) = s.parse() {
+            Ok(MulticastInterface::Address(a))
+        } else if let Ok(i) = s.parse() {
+            Ok(MulticastInterface::Index(i))
+        } else {
+            Ok(MulticastInterface::Name(s.to_string()))
+        }
+    }
+}
+
+/// Joins the multicast group, with SO_REUSEADDR (and SO_REUSEPORT on Unix)
+/// set so other listeners on the host can join it too.
+/// Without an interface the OS picks one.
+pub fn multicast_socket(
+    group: SocketAddr,
+    interface: Option<&MulticastInterface>,
+) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+
+    match group.ip() {
+        IpAddr::V4(g) => match interface {
+            None => socket.join_multicast_v4(&g, &Ipv4Addr::UNSPECIFIED)?,
+            Some(MulticastInterface::Address(a)) => socket.join_multicast_v4(&g, a)?,
+            Some(i) => socket
+                .join_multicast_v4_n(&g, &socket2::InterfaceIndexOrAddress::Index(i.index()?))?,
+        },
+        IpAddr::V6(g) => {
+            let idx = interface.map(|i| i.index()).transpose()?.unwrap_or(0);
+            socket.join_multicast_v6(&g, idx)?;
+        }
+    }
+
+    // Binding to the group keeps out the datagrams of other groups using the
+    // same port, Windows only allows binding to a local address
+    #[cfg(unix)]
+    let bind_addr = group;
+    #[cfg(not(unix))]
+    let bind_addr = match group {
+        SocketAddr::V4(a) => SocketAddr::from((Ipv4Addr::UNSPECIFIED, a.port())),
+        SocketAddr::V6(a) => SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, a.port())),
+    };
+    socket.bind(&bind_addr.into())?;
+    Ok(socket)
+}
+
+/// Beyond this many peers, the least recently seen one is evicted,
+/// e.g. a target that rebooted with another source port
+const MAX_PEERS: usize = 64;
+
+/// Peers warned about as rejected, they're warned about again once forgotten
+const MAX_REJECTED: usize = 1024;
+
+/// Largest UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65_536;
+
+/// A peer's bytes are dropped when this many are buffered without
+/// a complete packet, it's probably not sending CTF
+const MAX_PEER_BUFFERED: usize = 16 * 1024 * 1024;
+
+pub type CodecFactory = Box<dyn FnMut() -> Result<CtfPacketCodec, DecoderError> + Send>;
+
+struct Peer {
+    codec: CtfPacketCodec,
+    buf: BytesMut,
+    reassembler: Option<Reassembler>,
+    last_seen: Instant,
+}
+
+impl Peer {
+    /// Logs the packets and fragments of the peer that were dropped
+    fn report_drops(&self, addr: &SocketAddr) {
+        let source = format!("UDP peer {}", addr);
+        if let Some(r) = self.reassembler.as_ref() {
+            r.report_drops(&source);
+        }
+        self.codec.report_drops(&source);
+    }
+}
+
+/// Decodes the datagrams of each peer with its own codec
+pub struct PeerDecoder {
+    socket: UdpSocket,
+    source: UdpSource,
+    new_codec: CodecFactory,
+    peers: HashMap<SocketAddr, Peer>,
+    /// Evicted peers, the packets they have buffered are decoded before they're dropped
+    evicted: VecDeque<(SocketAddr, Peer)>,
+    /// Peers whose datagrams are dropped, warned about once
+    rejected: HashSet<SocketAddr>,
+    /// Peers whose buffers may hold more packets
+    ready: VecDeque<SocketAddr>,
+    datagram: Vec<u8>,
+    shutdown: broadcast::Receiver<()>,
+    /// Shutting down, decoding the datagrams already received
+    draining: bool,
+}
+
+impl PeerDecoder {
+    pub fn new(
+        socket: UdpSocket,
+        source: UdpSource,
+        new_codec: CodecFactory,
+        shutdown: broadcast::Receiver<()>,
+    ) -> Self {
+        Self {
+            socket,
+            source,
+            new_codec,
+            peers: HashMap::new(),
+            evicted: VecDeque::new(),
+            rejected: HashSet::new(),
+            ready: VecDeque::new(),
+            datagram: vec![0; MAX_DATAGRAM_SIZE],
+            shutdown,
+            draining: false,
+        }
+    }
+
+    /// Returns the next packet and the peer that sent it.
+    /// Returns None on shutdown, once the datagrams already received are decoded.
+    pub async fn next(&mut self) -> Option<Result<(CtfPacket, SocketAddr), DecoderError>> {
+        loop {
+            if let Some((addr, peer)) = self.evicted.front_mut() {
+                match peer.codec.decode(&mut peer.buf) {
+                    Ok(Some(pkt)) => return Some(Ok((pkt, *addr))),
+                    Ok(None) => (),
+                    Err(e) => return Some(Err(e)),
+                }
+                peer.report_drops(addr);
+                self.evicted.pop_front();
+                continue;
+            }
+            if let Some(addr) = self.ready.front().copied() {
+                if let Some(peer) = self.peers.get_mut(&addr) {
+                    match peer.codec.decode(&mut peer.buf) {
+                        Ok(Some(pkt)) => return Some(Ok((pkt, addr))),
+                        Ok(None) => (),
+                        Err(e) => return Some(Err(e)),
+                    }
+                    if peer.buf.len() > MAX_PEER_BUFFERED {
+                        warn!(
+                            "Dropping {} bytes from UDP peer {} without a complete packet",
+                            peer.buf.len(),
+                            addr
+                        );
+                        peer.buf.clear();
+                    }
+                }
+                self.ready.pop_front();
+                continue;
+            }
+            if self.draining {
+                for (addr, peer) in self.peers.iter() {
+                    peer.report_drops(addr);
+                }
+                return None;
+            }
+
+            let deadline = self.reassembly_deadline();
+            let expired = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into());
+            let (len, addr) = tokio::select! {
+                res = self.socket.recv_from(&mut self.datagram) => match res {
+                    Ok(r) => r,
+                    Err(e) => return Some(Err(e.into())),
+                },
+                _ = expired, if deadline.is_some() => {
+                    self.expire_fragments();
+                    continue;
+                }
+                _ = self.shutdown.recv() => {
+                    self.drain();
+                    continue;
+                }
+            };
+            if !self.source.allows(&addr) {
+                self.reject(addr, "isn't allowed");
+                continue;
+            }
+            let now = Instant::now();
+            if !self.peers.contains_key(&addr) {
+                if self.peers.len() >= MAX_PEERS {
+                    self.evict_least_recently_seen();
+                }
+                debug!("Decoding the packets of UDP peer {}", addr);
+                let codec = match (self.new_codec)() {
+                    Ok(c) => c,
+                    Err(e) => return Some(Err(e)),
+                };
+                let peer = Peer {
+                    codec,
+                    buf: BytesMut::new(),
+                    reassembler: self.source.reassembly.map(Reassembler::new),
+                    last_seen: now,
+                };
+                self.peers.insert(addr, peer);
+            }
+            if let Some(peer) = self.peers.get_mut(&addr) {
+                peer.last_seen = now;
+                let datagram = &self.datagram[..len];
+                match peer.reassembler.as_mut() {
+                    Some(r) => r.push(datagram, now, &mut peer.buf),
+                    None => peer.buf.extend_from_slice(datagram),
+                }
+                self.ready.push_back(addr);
+            }
+        }
+    }
+
+    fn reassembly_deadline(&self) -> Option<Instant> {
+        self.peers
+            .values()
+            .filter_map(|p| p.reassembler.as_ref()?.deadline())
+            .min()
+    }
+
+    /// Releases the packets held back by fragments that didn't arrive in time
+    fn expire_fragments(&mut self) {
+        let now = Instant::now();
+        for (addr, peer) in self.peers.iter_mut() {
+            if let Some(r) = peer.reassembler.as_mut() {
+                let len = peer.buf.len();
+                r.expire(now, &mut peer.buf);
+                if peer.buf.len() != len {
+                    self.ready.push_back(*addr);
+                }
+            }
+        }
+    }
+
+    /// Stops receiving, the complete packets held for reassembly are released
+    fn drain(&mut self) {
+        self.draining = true;
+        for (addr, peer) in self.peers.iter_mut() {
+            if let Some(r) = peer.reassembler.as_mut() {
+                r.flush(&mut peer.buf);
+            }
+            self.ready.push_back(*addr);
+        }
+    }
+
+    /// The complete packets held for reassembly are released to be decoded
+    fn evict_least_recently_seen(&mut self) {
+        let addr = match self.peers.iter().min_by_key(|(_, p)| p.last_seen) {
+            Some((addr, _)) => *addr,
+            None => return,
+        };
+        if let Some(mut peer) = self.peers.remove(&addr) {
+            debug!(
+                "Evicting UDP peer {}, last seen {:?} ago",
+                addr,
+                peer.last_seen.elapsed()
+            );
+            if let Some(r) = peer.reassembler.as_mut() {
+                r.flush(&mut peer.buf);
+            }
+            self.evicted.push_back((addr, peer));
+        }
+    }
+
+    fn reject(&mut self, addr: SocketAddr, reason: &str) {
+        if self.rejected.len() >= MAX_REJECTED && !self.rejected.contains(&addr) {
+            self.rejected.clear();
+        }
+        if self.rejected.insert(addr) {
+            warn!("Dropping datagrams from UDP peer {}, it {}", addr, reason);
+        } else {
+            debug!("Dropped a datagram from UDP peer {}", addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn multicast_interfaces() {
+        assert_eq!(
+            "eth1".parse(),
+            Ok(MulticastInterface::Name("eth1".to_string()))
+        );
+        assert_eq!("3".parse(), Ok(MulticastInterface::Index(3)));
+        assert_eq!(
+            "10.0.0.2".parse(),
+            Ok(MulticastInterface::Address(Ipv4Addr::new(10, 0, 0, 2)))
+        );
+        assert!(MulticastInterface::Address(Ipv4Addr::LOCALHOST)
+            .index()
+            .is_err());
+    }
+
+    #[test]
+    fn udp_source_urls() {
+        let url = Url::parse("udp://0.0.0.0:5000?allow=10.0.0.2,10.0.0.3:4000").unwrap();
+        let src = UdpSource::from_url(&url).unwrap();
+        assert!(src.allows(&"10.0.0.2:1234".parse().unwrap()));
+        assert!(src.allows(&"10.0.0.3:4000".parse().unwrap()));
+        assert!(!src.allows(&"10.0.0.3:4001".parse().unwrap()));
+        assert_eq!(src.to_string(), url.as_str());
+
+        let url = Url::parse("udp://239.1.2.3:5000?iface=eth1").unwrap();
+        let src = UdpSource::from_url(&url).unwrap();
+        assert!(src.is_multicast());
+        assert!(src.allows(&"10.0.0.9:1".parse().unwrap()));
+        assert_eq!(src.to_string(), url.as_str());
+
+        let url = Url::parse("udp://10.0.0.1:5000?iface=eth1").unwrap();
+        assert!(UdpSource::from_url(&url).is_err());
+
+        let url = Url::parse("udp://0.0.0.0:5000?reassembly&reassembly_timeout=250").unwrap();
+        let src = UdpSource::from_url(&url).unwrap();
+        assert_eq!(
+            src.reassembly,
+            Some(ReassemblyOpts {
+                window: ReassemblyOpts::default().window,
+                timeout: Duration::from_millis(250),
+            })
+        );
+        assert_eq!(src.to_string(), url.as_str());
+    }
+}
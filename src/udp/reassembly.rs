@@ -0,0 +1,321 @@
+//! Reassembly of CTF packets fragmented across UDP datagrams
+//!
+//! Each datagram starts with an 8 byte big-endian fragment header:
+//!
+//! | Offset | Size | Field                                               |
+//! |--------|------|-----------------------------------------------------|
+//! | 0      | 4    | Packet sequence number, one per packet, wrapping    |
+//! | 4      | 2    | Fragment index, from 0                              |
+//! | 6      | 2    | Fragment count                                      |
+//!
+//! The packets are released in sequence order. A packet still incomplete
+//! after the timeout, or pushed out of the window by newer packets, is dropped.
+
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub const FRAGMENT_HEADER_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ReassemblyOpts {
+    /// Packets being reassembled at once
+    pub window: u32,
+    /// How long a packet waits for its missing fragments
+    pub timeout: Duration,
+}
+
+impl Default for ReassemblyOpts {
+    fn default() -> Self {
+        Self {
+            window: 32,
+            timeout: Duration::from_millis(1000),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ReassemblyStats {
+    /// Packets dropped with fragments missing
+    pub incomplete: u64,
+    /// Fragments of packets already released or dropped, and duplicates
+    pub late: u64,
+    /// Datagrams with an invalid fragment header
+    pub malformed: u64,
+}
+
+struct Partial {
+    fragments: Vec<Option<Bytes>>,
+    missing: usize,
+    first_seen: Instant,
+}
+
+pub struct Reassembler {
+    opts: ReassemblyOpts,
+    /// Sequence number of the next packet to release
+    next_seq: Option<u32>,
+    pending: HashMap<u32, Partial>,
+    stats: ReassemblyStats,
+}
+
+impl Reassembler {
+    pub fn new(opts: ReassemblyOpts) -> Self {
+        Self {
+            opts,
+            next_seq: None,
+            pending: HashMap::new(),
+            stats: ReassemblyStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Logs the fragments dropped so far, if any
+    pub fn report_drops(&self, source: &dyn fmt::Display) {
+        let ReassemblyStats {
+            incomplete,
+            late,
+            malformed,
+        } = self.stats;
+        if incomplete != 0 {
+            warn!(
+                "Dropped {} packets from {} because some of their fragments were missing",
+                incomplete, source
+            );
+        }
+        if late != 0 {
+            warn!(
+                "Dropped {} fragments from {} because they were late or duplicated",
+                late, source
+            );
+        }
+        if malformed != 0 {
+            warn!(
+                "Dropped {} fragments from {} because their header was invalid",
+                malformed, source
+            );
+        }
+    }
+
+    /// When the oldest pending packet times out
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|p| p.first_seen + self.opts.timeout)
+            .min()
+    }
+
+    /// The packets completed by the fragment are appended to `out`
+    pub fn push(&mut self, datagram: &[u8], now: Instant, out: &mut BytesMut) {
+        let (seq, index, count, payload) = match parse_fragment(datagram) {
+            Some(f) => f,
+            None => {
+                self.stats.malformed += 1;
+                log_drop(self.stats.malformed, "a malformed fragment");
+                return;
+            }
+        };
+        let next = *self.next_seq.get_or_insert(seq);
+        let offset = seq.wrapping_sub(next);
+        let max_reordering = self.opts.window.saturating_mul(2);
+        if offset > u32::MAX / 2 && next.wrapping_sub(seq) <= max_reordering {
+            self.drop_late(seq);
+            return;
+        }
+        if offset >= max_reordering {
+            // Too far ahead or behind to be reordering, e.g. the target restarted
+            // its sequence numbers
+            debug!("Fragment sequence jumped from {} to {}", next, seq);
+            self.drop_pending(self.pending.len() as u64);
+            self.pending.clear();
+            self.next_seq = Some(seq);
+        } else if offset >= self.opts.window {
+            // Give up on the packets pushed out of the window
+            while self
+                .next_seq
+                .is_some_and(|next| seq.wrapping_sub(next) >= self.opts.window)
+            {
+                self.drop_head();
+                self.release(out);
+            }
+        }
+
+        let partial = self.pending.entry(seq).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            missing: count as usize,
+            first_seen: now,
+        });
+        if partial.fragments.len() != count as usize {
+            self.stats.malformed += 1;
+            log_drop(self.stats.malformed, "a fragment with a mismatched count");
+            return;
+        }
+        match partial.fragments.get_mut(index as usize) {
+            Some(f @ None) => {
+                *f = Some(Bytes::copy_from_slice(payload));
+                partial.missing -= 1;
+            }
+            _ => {
+                self.drop_late(seq);
+                return;
+            }
+        }
+        self.release(out);
+    }
+
+    /// Drops the packets blocking the release of those that waited too long
+    pub fn expire(&mut self, now: Instant, out: &mut BytesMut) {
+        while self.deadline().is_some_and(|d| d <= now) {
+            self.drop_head();
+            self.release(out);
+        }
+    }
+
+    /// Releases the complete packets, the incomplete ones are dropped
+    pub fn flush(&mut self, out: &mut BytesMut) {
+        self.release(out);
+        while !self.pending.is_empty() {
+            self.drop_head();
+            self.release(out);
+        }
+    }
+
+    /// Appends the complete packets at the head of the sequence
+    fn release(&mut self, out: &mut BytesMut) {
+        while let Some(next) = self.next_seq {
+            match self.pending.get(&next) {
+                Some(p) if p.missing == 0 => (),
+                _ => break,
+            }
+            if let Some(p) = self.pending.remove(&next) {
+                for f in p.fragments.into_iter().flatten() {
+                    out.extend_from_slice(&f);
+                }
+            }
+            self.next_seq = Some(next.wrapping_add(1));
+        }
+    }
+
+    /// Gives up on the next packet, whether some of its fragments arrived or none
+    fn drop_head(&mut self) {
+        if let Some(next) = self.next_seq {
+            self.pending.remove(&next);
+            self.drop_pending(1);
+            self.next_seq = Some(next.wrapping_add(1));
+        }
+    }
+
+    fn drop_pending(&mut self, packets: u64) {
+        if packets != 0 {
+            self.stats.incomplete += packets;
+            log_drop(self.stats.incomplete, "an incomplete packet");
+        }
+    }
+
+    fn drop_late(&mut self, seq: u32) {
+        self.stats.late += 1;
+        debug!(
+            "Dropped a late fragment of packet {}, {} so far",
+            seq, self.stats.late
+        );
+    }
+}
+
+fn log_drop(count: u64, what: &str) {
+    if count == 1 {
+        warn!("Dropping {} received over UDP", what);
+    } else {
+        debug!("Dropped {}, {} so far", what, count);
+    }
+}
+
+fn parse_fragment(datagram: &[u8]) -> Option<(u32, u16, u16, &[u8])> {
+    if datagram.len() < FRAGMENT_HEADER_SIZE {
+        return None;
+    }
+    let (hdr, payload) = datagram.split_at(FRAGMENT_HEADER_SIZE);
+    let seq = u32::from_be_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+    let index = u16::from_be_bytes([hdr[4], hdr[5]]);
+    let count = u16::from_be_bytes([hdr[6], hdr[7]]);
+    if index >= count {
+        return None;
+    }
+    Some((seq, index, count, payload))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fragment(seq: u32, index: u16, count: u16, payload: &[u8]) -> Vec<u8> {
+        let mut d = Vec::new();
+        d.extend_from_slice(&seq.to_be_bytes());
+        d.extend_from_slice(&index.to_be_bytes());
+        d.extend_from_slice(&count.to_be_bytes());
+        d.extend_from_slice(payload);
+        d
+    }
+
+    #[test]
+    fn reassemble_fragments() {
+        let opts = ReassemblyOpts {
+            window: 4,
+            timeout: Duration::from_millis(100),
+        };
+        let mut r = Reassembler::new(opts);
+        let mut out = BytesMut::new();
+        let t0 = Instant::now();
+
+        // Reordered fragments and packets
+        r.push(&fragment(10, 1, 2, b"b"), t0, &mut out);
+        r.push(&fragment(11, 0, 1, b"c"), t0, &mut out);
+        assert!(out.is_empty());
+        r.push(&fragment(10, 0, 2, b"a"), t0, &mut out);
+        assert_eq!(&out[..], b"abc");
+        r.push(&fragment(10, 0, 2, b"a"), t0, &mut out);
+        assert_eq!(r.stats().late, 1);
+
+        // Packet 12 never completes, 13 is released after the timeout
+        out.clear();
+        r.push(&fragment(12, 0, 2, b"d"), t0, &mut out);
+        r.push(&fragment(13, 0, 1, b"e"), t0, &mut out);
+        r.expire(t0 + Duration::from_millis(50), &mut out);
+        assert!(out.is_empty());
+        r.expire(t0 + Duration::from_millis(100), &mut out);
+        assert_eq!(&out[..], b"e");
+        assert_eq!(r.stats().incomplete, 1);
+        assert_eq!(r.deadline(), None);
+
+        // Packet 14 is lost, 18 pushes it out of the window
+        out.clear();
+        for seq in 15..=18 {
+            r.push(&fragment(seq, 0, 1, &[seq as u8]), t0, &mut out);
+        }
+        assert_eq!(&out[..], &[15, 16, 17, 18]);
+        assert_eq!(r.stats().incomplete, 2);
+
+        r.push(&[0, 0, 0, 19, 0, 1, 0, 1], t0, &mut out);
+        assert_eq!(r.stats().malformed, 1);
+
+        // Packet 19 is incomplete, 20 is released on shutdown
+        out.clear();
+        r.push(&fragment(19, 0, 2, b"f"), t0, &mut out);
+        r.push(&fragment(20, 0, 1, b"g"), t0, &mut out);
+        r.flush(&mut out);
+        assert_eq!(&out[..], b"g");
+        assert_eq!(r.stats().incomplete, 3);
+
+        // The target restarted, its sequence starts over from 0
+        out.clear();
+        r.push(&fragment(21, 0, 2, b"h"), t0, &mut out);
+        r.push(&fragment(0, 0, 1, b"i"), t0, &mut out);
+        r.push(&fragment(1, 0, 1, b"j"), t0, &mut out);
+        assert_eq!(&out[..], b"ij");
+        assert_eq!(r.stats().incomplete, 4);
+        assert_eq!(r.stats().late, 1);
+    }
+}